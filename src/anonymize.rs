@@ -0,0 +1,171 @@
+use anyhow::{Context, Error, Result};
+use hmac::{Hmac, Mac};
+use ipnet::IpNet;
+use regex::{Captures, Regex};
+use sha2::Sha256;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::OnceLock;
+
+/// How much of an ipv4 and ipv6 address is kept when truncating
+const IPV4_PREFIX: u8 = 24;
+const IPV6_PREFIX: u8 = 48;
+
+/// What's stored instead of a redacted value
+const REDACTED: &str = "REDACTED";
+
+/// A `name=value` pair of a query string, with what comes before it
+fn param_regex() -> &'static Regex {
+    static PARAM: OnceLock<Regex> = OnceLock::new();
+    PARAM.get_or_init(|| Regex::new(r"(^|[?&;])([^?&;#=\s]+)=([^&;#\s]*)").unwrap())
+}
+
+/// What happens to the ip addresses before they're stored
+#[derive(Clone, Default)]
+pub enum IpMode {
+    #[default]
+    Keep,
+    /// Zero the host part, 1.2.3.4 → 1.2.3.0
+    Truncate,
+    /// Replace with an address made from a keyed hash, the same for the same address
+    Hmac(Vec<u8>),
+}
+
+/// Removes the personal data that shouldn't be stored, after the lines are enriched and filtered
+#[derive(Clone, Default)]
+pub struct Anonymizer {
+    pub ips: IpMode,
+    /// The names of the query parameters whose values are redacted
+    params: Vec<String>,
+    /// All of them in one, matching the whole name
+    redact: Option<Regex>,
+}
+
+impl Anonymizer {
+    pub fn is_enabled(&self) -> bool {
+        self.hides_ips() || self.redact.is_some()
+    }
+
+    /// Adds a query parameter to redact, as a regex matching the whole name like `token|.*_key`
+    pub fn redact_param(&mut self, name: &str) -> Result<(), Error> {
+        Regex::new(name).with_context(|| format!("Invalid regex {}", name))?;
+        self.params.push(name.to_string());
+        let names: Vec<String> = self.params.iter().map(|p| format!("(?:{})", p)).collect();
+        self.redact = Some(Regex::new(&format!(r"(?i)^(?:{})$", names.join("|")))?);
+        Ok(())
+    }
+
+    /// Whether the addresses are replaced, anything that only looks like one is left out then
+    pub fn hides_ips(&self) -> bool {
+        !matches!(self.ips, IpMode::Keep)
+    }
+
+    pub fn params(&self) -> &[String] {
+        &self.params
+    }
+
+    pub fn ip(&self, ip: IpAddr) -> IpAddr {
+        match &self.ips {
+            IpMode::Keep => ip,
+            IpMode::Truncate => {
+                let prefix = if ip.is_ipv4() {
+                    IPV4_PREFIX
+                } else {
+                    IPV6_PREFIX
+                };
+                IpNet::new(ip, prefix).map(|n| n.network()).unwrap_or(ip)
+            }
+            IpMode::Hmac(key) => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("Any key length works");
+                match ip {
+                    IpAddr::V4(v4) => mac.update(&v4.octets()),
+                    IpAddr::V6(v6) => mac.update(&v6.octets()),
+                }
+                let hash = mac.finalize().into_bytes();
+                // Stays an address of the same family, so it still fits the ip mapping
+                match ip {
+                    IpAddr::V4(_) => {
+                        IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&hash[..4]).unwrap()))
+                    }
+                    IpAddr::V6(_) => {
+                        IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&hash[..16]).unwrap()))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replaces the values of the redacted query parameters, in a url, request line or query string
+    pub fn redact(&self, text: &str) -> String {
+        let Some(names) = &self.redact else {
+            return text.to_string();
+        };
+        param_regex()
+            .replace_all(text, |cap: &Captures| match names.is_match(&cap[2]) {
+                true => format!("{}{}={}", &cap[1], &cap[2], REDACTED),
+                false => cap[0].to_string(),
+            })
+            .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(str: &str) -> IpAddr {
+        str.parse().unwrap()
+    }
+
+    #[test]
+    fn truncated_ips() {
+        let anonymizer = Anonymizer {
+            ips: IpMode::Truncate,
+            ..Default::default()
+        };
+        assert_eq!(anonymizer.ip(ip("84.213.100.23")), ip("84.213.100.0"));
+        assert_eq!(
+            anonymizer.ip(ip("2001:db8:85a3:8d3:1319:8a2e:370:7348")),
+            ip("2001:db8:85a3::")
+        );
+    }
+
+    #[test]
+    fn hmac_ips() {
+        let anonymizer = Anonymizer {
+            ips: IpMode::Hmac(b"secret".to_vec()),
+            ..Default::default()
+        };
+        let other = Anonymizer {
+            ips: IpMode::Hmac(b"other".to_vec()),
+            ..Default::default()
+        };
+        let v4 = anonymizer.ip(ip("84.213.100.23"));
+        assert!(v4.is_ipv4());
+        assert_ne!(v4, ip("84.213.100.23"));
+        assert_eq!(v4, anonymizer.ip(ip("84.213.100.23")));
+        assert_ne!(v4, anonymizer.ip(ip("84.213.100.24")));
+        assert_ne!(v4, other.ip(ip("84.213.100.23")));
+        assert!(anonymizer.ip(ip("::1")).is_ipv6());
+    }
+
+    #[test]
+    fn redacted_params() {
+        let mut anonymizer = Anonymizer::default();
+        assert_eq!(anonymizer.redact("/a?token=1"), "/a?token=1");
+        anonymizer.redact_param("token").unwrap();
+        anonymizer.redact_param(".*_key").unwrap();
+        assert!(anonymizer.redact_param("(").is_err());
+        assert_eq!(
+            anonymizer.redact("GET /a?Token=1&page=2&api_key=x HTTP/1.1"),
+            "GET /a?Token=REDACTED&page=2&api_key=REDACTED HTTP/1.1"
+        );
+        assert_eq!(
+            anonymizer.redact("token=1;mytoken=2"),
+            "token=REDACTED;mytoken=2"
+        );
+        assert_eq!(
+            anonymizer.redact("https://example.com/?token=1#top"),
+            "https://example.com/?token=REDACTED#top"
+        );
+    }
+}
@@ -1,25 +1,65 @@
 use std::{
-    fs::File,
+    fs,
     io::{BufRead, BufReader},
     path::PathBuf,
 };
 
 use anyhow::{bail, Error};
+use elasticsearch::auth::ClientCertificate;
+use openssl::{asn1::Asn1Time, pkcs12::Pkcs12, pkey::PKey, stack::Stack, x509::X509};
 
 pub struct Cert {
-    pub cert: reqwest::Certificate,
+    /// Every certificate in the bundle
+    pub certs: Vec<reqwest::Certificate>,
     pub es_cert: elasticsearch::cert::Certificate,
+    pub info: Vec<CertInfo>,
     pem_data: Vec<u8>,
 }
 
+/// What gets reported about each certificate at startup
+#[derive(Clone)]
+pub struct CertInfo {
+    pub subject: String,
+    pub not_after: String,
+    /// Negative when already expired
+    pub days_left: i32,
+}
+
+impl CertInfo {
+    fn new(cert: &X509) -> Result<Self, Error> {
+        let subject = cert
+            .subject_name()
+            .entries()
+            .map(|e| {
+                let name = e.object().nid().short_name().unwrap_or("?");
+                let value = e
+                    .data()
+                    .as_utf8()
+                    .map(|v| v.to_string())
+                    .unwrap_or_default();
+                format!("{}={}", name, value)
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+        let days_left = Asn1Time::days_from_now(0)?.diff(cert.not_after())?.days;
+
+        Ok(CertInfo {
+            subject,
+            not_after: cert.not_after().to_string(),
+            days_left,
+        })
+    }
+}
+
 impl Clone for Cert {
     fn clone(&self) -> Self {
         let es_cert = elasticsearch::cert::Certificate::from_pem(&self.pem_data).unwrap();
         Self {
-            cert: self.cert.clone(),
+            certs: self.certs.clone(),
             // ??
             //es_cert: self.es_cert.clone(),
             es_cert,
+            info: self.info.clone(),
             pem_data: self.pem_data.clone(),
         }
     }
@@ -27,50 +67,264 @@ impl Clone for Cert {
 impl Cert {
     pub fn new(t: PathBuf) -> Result<Self, Error> {
         let pem_data = path_to_cert_data(t)?;
-        let cert = reqwest::Certificate::from_pem(&pem_data)?;
+        let certs = reqwest::Certificate::from_pem_bundle(&pem_data)?;
         let es_cert = elasticsearch::cert::Certificate::from_pem(&pem_data)?;
+        let info = X509::stack_from_pem(&pem_data)?
+            .iter()
+            .map(CertInfo::new)
+            .collect::<Result<Vec<CertInfo>, Error>>()?;
 
         return Ok(Cert {
-            cert,
+            certs,
             es_cert,
+            info,
             pem_data,
         });
     }
 }
 
+/// Client certificate used for mutual TLS, kept as PKCS#12
+/// since that is what the native-tls backend of both clients wants
+#[derive(Clone)]
+pub struct ClientIdentity {
+    pkcs12: Vec<u8>,
+    password: String,
+}
+
+impl ClientIdentity {
+    /// Accepts either a PKCS#12 archive (with an optional password),
+    /// or a PEM certificate together with a PEM private key
+    pub fn new(
+        cert: PathBuf,
+        key: Option<PathBuf>,
+        password: Option<String>,
+    ) -> Result<Self, Error> {
+        let cert_data = fs::read(&cert)?;
+        let password = password.unwrap_or_default();
+
+        let key = match key {
+            Some(k) => k,
+            None => {
+                // Has to be a PKCS#12 archive then
+                Pkcs12::from_der(&cert_data)?.parse(&password)?;
+                return Ok(ClientIdentity {
+                    pkcs12: cert_data,
+                    password,
+                });
+            }
+        };
+
+        let key = PKey::private_key_from_pem(&fs::read(key)?)?;
+        let mut chain = X509::stack_from_pem(&cert_data)?.into_iter();
+        let leaf = match chain.next() {
+            Some(c) => c,
+            None => bail!("No certificate found in {:?}", cert),
+        };
+        let mut ca = Stack::new()?;
+        for c in chain {
+            ca.push(c)?;
+        }
+
+        let mut builder = Pkcs12::builder();
+        builder.ca(ca);
+        let pkcs12 = builder
+            .build(&password, "nginx-es", &key, &leaf)?
+            .to_der()?;
+
+        Ok(ClientIdentity { pkcs12, password })
+    }
+
+    pub fn identity(&self) -> Result<reqwest::Identity, Error> {
+        Ok(reqwest::Identity::from_pkcs12_der(
+            &self.pkcs12,
+            &self.password,
+        )?)
+    }
+
+    pub fn es_certificate(&self) -> ClientCertificate {
+        ClientCertificate::Pkcs12(self.pkcs12.clone(), Some(self.password.clone()))
+    }
+}
+
+/// Certificate files are small, anything bigger is most likely a log file
+const MAX_CERT_FILE_SIZE: u64 = 1024 * 1024;
+
 pub fn path_to_cert_data(path: PathBuf) -> Result<Vec<u8>, Error> {
-    // Old method
-    // let mut buf = Vec::new();
-    // File::open(path).ok()?.read_to_end(&mut buf).ok()?;
-    // let cert = reqwest::Certificate::from_pem(&buf).ok()?;
-    // Some(cert)
-
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-    let mut found_cert = false;
+    // Exit early preventing reading of HUGE files
+    if fs::metadata(&path)?.len() > MAX_CERT_FILE_SIZE {
+        bail!("Too big to be a cert file");
+    }
+    let data = fs::read(&path)?;
+
+    // A DER file is a single binary certificate,
+    // converted to PEM so the rest only has to deal with one format
+    if let Ok(cert) = X509::from_der(&data) {
+        return Ok(cert.to_pem()?);
+    }
+
+    let reader = BufReader::new(data.as_slice());
+    let mut in_cert = false;
     let mut cert_data = Vec::new();
 
+    // Picks out every certificate, skipping comments, `Bag Attributes` preambles,
+    // subject=/issuer= lines and whatever else is in between
     for line in reader.lines() {
-        let line = line?.trim().to_string();
+        let line = match line {
+            Ok(l) => l.trim().to_string(),
+            Err(_) => bail!("Malformed cert file"),
+        };
         if line.starts_with("-----BEGIN CERTIFICATE-----") {
-            found_cert = true;
+            in_cert = true;
         }
-        if !line.is_empty() && !found_cert {
-            // Exit early preventing reading of HUGE files
-            bail!("Malformed cert file");
-        }
-        if found_cert {
+        if in_cert {
             cert_data.extend_from_slice(line.as_bytes());
             cert_data.push(b'\n');
             if line.starts_with("-----END CERTIFICATE-----") {
-                break;
+                in_cert = false;
             }
         }
     }
 
-    if found_cert {
-        Ok(cert_data)
-    } else {
+    if cert_data.is_empty() {
         bail!("No certificate found in file")
+    } else if in_cert {
+        bail!("Certificate is missing its END line")
+    } else {
+        Ok(cert_data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        bn::BigNum,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        x509::X509NameBuilder,
+    };
+
+    /// A small self-signed certificate, valid for 30 days
+    fn fixture(name: &str) -> X509 {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(30).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    fn pem(cert: &X509) -> String {
+        String::from_utf8(cert.to_pem().unwrap()).unwrap()
+    }
+
+    /// A file in a directory of its own, removed when dropped
+    struct Fixture {
+        dir: PathBuf,
+        path: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str, data: &[u8]) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "nginx-es-test-cert-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir(&dir).unwrap();
+            let path = dir.join(name);
+            fs::write(&path, data).unwrap();
+            Fixture { dir, path }
+        }
+
+        fn path(&self) -> PathBuf {
+            self.path.clone()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn bundle_with_preambles() {
+        let (root, intermediate) = (fixture("root"), fixture("intermediate"));
+        // What `openssl pkcs12 -nokeys` writes, plus a comment
+        let bundle = format!(
+            "# CA bundle for the cluster\n\
+             Bag Attributes\n    friendlyName: root\n\
+             subject=CN = root\n\
+             issuer=CN = root\n\
+             {}\n\
+             Bag Attributes\n    friendlyName: intermediate\n\
+             subject=CN = intermediate\n\
+             issuer=CN = intermediate\n\
+             {}",
+            pem(&root),
+            pem(&intermediate)
+        );
+        let file = Fixture::new("bundle.pem", bundle.as_bytes());
+
+        let data = path_to_cert_data(file.path()).unwrap();
+        assert_eq!(
+            String::from_utf8(data).unwrap(),
+            pem(&root) + &pem(&intermediate)
+        );
+
+        let cert = Cert::new(file.path()).unwrap();
+        assert_eq!(cert.certs.len(), 2);
+        let subjects: Vec<&str> = cert.info.iter().map(|i| i.subject.as_str()).collect();
+        assert_eq!(subjects, ["CN=root", "CN=intermediate"]);
+        assert!((29..=30).contains(&cert.info[0].days_left));
+    }
+
+    #[test]
+    fn der_is_converted_to_pem() {
+        let root = fixture("root");
+        let file = Fixture::new("root.der", &root.to_der().unwrap());
+        assert_eq!(
+            path_to_cert_data(file.path()).unwrap(),
+            root.to_pem().unwrap()
+        );
+        assert_eq!(Cert::new(file.path()).unwrap().info[0].subject, "CN=root");
+    }
+
+    #[test]
+    fn broken_files() {
+        let missing = std::env::temp_dir().join("nginx-es-test-cert-missing.pem");
+        assert!(path_to_cert_data(missing).is_err());
+
+        let file = Fixture::new("empty.pem", b"# no certificates here\n");
+        // A directory has a size, but can't be read
+        assert!(path_to_cert_data(file.dir.clone()).is_err());
+        let error = path_to_cert_data(file.path()).unwrap_err();
+        assert_eq!(error.to_string(), "No certificate found in file");
+
+        let root = pem(&fixture("root"));
+        let cut = &root[..root.len() - "-----END CERTIFICATE-----\n".len()];
+        let file = Fixture::new("cut.pem", cut.as_bytes());
+        let error = path_to_cert_data(file.path()).unwrap_err();
+        assert_eq!(error.to_string(), "Certificate is missing its END line");
+
+        let file = Fixture::new("big.pem", &vec![b'#'; MAX_CERT_FILE_SIZE as usize + 1]);
+        assert!(path_to_cert_data(file.path()).is_err());
     }
 }
@@ -1,54 +1,378 @@
 use std::{
-    io::{stdout, Write},
+    collections::{BTreeMap, HashMap},
+    io::{stdin, stdout, IsTerminal, Write},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use colored::Colorize;
 
-use crate::{cert::Cert, logger::valid_log, server};
 use crate::{
-    server::Server,
-    utils::{beautify_path, valid_archive},
+    anonymize::{Anonymizer, IpMode},
+    cert::{Cert, ClientIdentity},
+    document::Document,
+    enrich::Enricher,
+    filter::{Action, Filter},
+    geoip::GeoDatabase,
+    index::{IndexOptions, Rollover},
+    json::JsonFields,
+    logger::{valid_log, IdStrategy},
+    output,
+    pipe::{is_fifo, Pipe},
+    pipeline::PipelineOptions,
+    proxy::TrustedProxies,
+    server,
+    source::{format_labels, parse_label, Labels, SourceOptions},
+    syslog::{SyslogListener, SyslogTag},
+    tail::{glob_files, is_glob, GLOB_INTERVAL},
+};
+use crate::{
+    output::{Destination, Output},
+    server::{ConnectionOptions, Server},
+    useragent::UserAgentParser,
+    utils::{beautify_path, read_secret, valid_archive, Prompt},
 };
 
 const DEFAULT_SERVERS: [&str; 1] = ["http://127.0.0.1:9200/logger"];
 const DEFAULT_ARCHIVE_FILE_PREFIX: &str = "nginx";
 const DEFAULT_BULK_SIZE: u32 = 500;
 
+/// Gets the value following a flag, exiting if it's missing
+fn flag_value<'a>(flag: &str, value: Option<&'a String>) -> &'a String {
+    match value {
+        Some(v) => v,
+        None => {
+            eprintln!("{}", format!("{} expects a value", flag).red());
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Reads the value following a flag as a secret, exiting if it's missing or unreadable
+fn secret_value(flag: &str, value: Option<&String>) -> String {
+    match read_secret(flag_value(flag, value)) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", format!("{}: {}", flag, e).red());
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Reads the value following a flag as a number above 0, exiting if it isn't one
+fn count_value(flag: &str, value: Option<&String>) -> usize {
+    let value = flag_value(flag, value);
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => n,
+        _ => {
+            eprintln!(
+                "{}",
+                format!("{} expects a number above 0, got {}", flag, value).red()
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// What nginx-es was asked to do
+#[derive(Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Tail the logs and index them
+    Run,
+    /// Bring the mapping of the servers up to date
+    Migrate { dry_run: bool },
+}
+
 #[derive(Clone)]
 pub struct Config {
+    pub command: Command,
     pub nginx_sources: Vec<PathBuf>,
-    pub server: Server,
+    /// Patterns like /var/log/nginx/*-access.log, whose files come and go
+    pub nginx_globs: Vec<String>,
+    /// Read from start to end, instead of tailed
+    pub pipes: Vec<Pipe>,
+    pub outputs: Vec<Arc<Output>>,
     pub archive_folder: Option<PathBuf>,
     pub archive_file_prefix: String,
-    pub bulk_size: u32,
+    pub enricher: Arc<Enricher>,
+    pub filter: Arc<Filter>,
+    pub anonymizer: Arc<Anonymizer>,
+    pub id_strategy: IdStrategy,
+    pub json_fields: JsonFields,
+    pub syslog: Vec<SyslogListener>,
+    /// Syslog tag → the index and parser for its lines
+    pub syslog_tags: HashMap<String, SyslogTag>,
+    /// Static fields for every document
+    pub labels: Labels,
+    /// Static fields and indices for some of the sources
+    pub sources: Vec<SourceOptions>,
+    pub pipeline: PipelineOptions,
 }
 
 impl Config {
-    pub fn new(args: Vec<String>) -> Self {
+    pub async fn new(args: Vec<String>) -> Self {
         let mut locations = vec![];
-        let mut servers = DEFAULT_SERVERS.to_vec();
+        let mut servers = vec![];
         let mut archiving = vec![];
         let mut archive_file_prefix = DEFAULT_ARCHIVE_FILE_PREFIX.to_string();
-        let mut cert_path: Option<PathBuf> = None;
+        let mut command = Command::Run;
+        let mut dry_run = false;
+        let mut connection = ConnectionOptions::default();
+        let mut index_options = IndexOptions {
+            retention_days: crate::ARCHIVE_AFTER_DAYS,
+            ..Default::default()
+        };
+        let mut client_cert: Option<PathBuf> = None;
+        let mut client_key: Option<PathBuf> = None;
+        let mut client_cert_password: Option<String> = None;
         let mut bulk_size: u32 = DEFAULT_BULK_SIZE;
+        let mut parse_user_agents = false;
+        let mut user_agent_regexes: Option<PathBuf> = None;
+        let mut geoip_city: Option<PathBuf> = None;
+        let mut geoip_asn: Option<PathBuf> = None;
+        let mut trusted_proxies = TrustedProxies::default();
+        let mut id_strategy = IdStrategy::default();
+        let mut json_fields = JsonFields::default();
+        let mut custom_json_fields: Vec<&str> = vec![];
+        let mut nginx_globs: Vec<String> = vec![];
+        let mut pipes: Vec<Pipe> = vec![];
+        let mut syslog: Vec<SyslogListener> = vec![];
+        let mut labels: BTreeMap<String, String> = BTreeMap::new();
+        let mut sources: Vec<SourceOptions> = vec![];
+        let mut filter = Filter::default();
+        let mut anonymizer = Anonymizer::default();
+        let mut syslog_tags: HashMap<String, SyslogTag> = HashMap::new();
+        let mut pipeline = PipelineOptions::default();
 
         let mut new_locations: Vec<&str> = vec![];
         let mut new_servers: Vec<&str> = vec![];
+        let mut new_files: Vec<&str> = vec![];
         let mut new_archiving: Vec<&str> = vec![];
 
         // Iterate arguments, skip executable
-        for arg in &args[1..] {
-            if Path::new(arg).is_dir() {
+        let mut args_iter = args[1..].iter();
+        while let Some(arg) = args_iter.next() {
+            // Flags are checked first, everything else is guessed
+            if arg.starts_with("--") {
+                match arg.as_str() {
+                    "--password" => {
+                        connection.password = Some(secret_value(arg, args_iter.next()));
+                    }
+                    "--api-key" => {
+                        connection.api_key = Some(secret_value(arg, args_iter.next()));
+                    }
+                    "--bearer-token" => {
+                        connection.bearer_token = Some(secret_value(arg, args_iter.next()));
+                    }
+                    "--client-cert" => {
+                        client_cert = Some(PathBuf::from(flag_value(arg, args_iter.next())));
+                    }
+                    "--client-key" => {
+                        client_key = Some(PathBuf::from(flag_value(arg, args_iter.next())));
+                    }
+                    "--client-cert-password" => {
+                        client_cert_password = Some(secret_value(arg, args_iter.next()));
+                    }
+                    "--dry-run" => {
+                        dry_run = true;
+                    }
+                    "--insecure" => {
+                        connection.insecure = true;
+                    }
+                    "--yes" => {
+                        index_options.prompt = Prompt::Yes;
+                    }
+                    "--data-stream" => {
+                        index_options.data_stream = true;
+                    }
+                    "--ilm" => {
+                        index_options.ilm = true;
+                    }
+                    "--parse-user-agents" => {
+                        parse_user_agents = true;
+                    }
+                    "--user-agent-regexes" => {
+                        parse_user_agents = true;
+                        user_agent_regexes = Some(PathBuf::from(flag_value(arg, args_iter.next())));
+                    }
+                    "--geoip" => {
+                        geoip_city = Some(PathBuf::from(flag_value(arg, args_iter.next())));
+                    }
+                    "--geoip-asn" => {
+                        geoip_asn = Some(PathBuf::from(flag_value(arg, args_iter.next())));
+                    }
+                    "--trusted-proxy" => {
+                        let value = flag_value(arg, args_iter.next());
+                        if let Err(e) = trusted_proxies.add(value) {
+                            eprintln!("{}", format!("--trusted-proxy: {}", e).red());
+                            std::process::exit(1);
+                        }
+                    }
+                    "--id-strategy" => {
+                        let value = flag_value(arg, args_iter.next());
+                        match IdStrategy::parse(value) {
+                            Some(s) => id_strategy = s,
+                            None => {
+                                eprintln!(
+                                    "{}",
+                                    format!(
+                                        "--id-strategy expects line, auto or time-ip, got {}",
+                                        value
+                                    )
+                                    .red()
+                                );
+                                std::process::exit(1);
+                            }
+                        }
+                    }
+                    "--json-field" => {
+                        let value = flag_value(arg, args_iter.next());
+                        if let Err(e) = json_fields.add(value) {
+                            eprintln!("{}", format!("--json-field: {}", e).red());
+                            std::process::exit(1);
+                        }
+                        custom_json_fields.push(value);
+                    }
+                    "--syslog" => {
+                        let value = flag_value(arg, args_iter.next());
+                        match SyslogListener::parse(value) {
+                            Ok(l) => syslog.push(l),
+                            Err(e) => {
+                                eprintln!("{}", format!("--syslog: {}", e).red());
+                                std::process::exit(1);
+                            }
+                        }
+                    }
+                    "--syslog-tag" => {
+                        let value = flag_value(arg, args_iter.next());
+                        match SyslogTag::parse(value) {
+                            Ok((tag, options)) => {
+                                syslog_tags.insert(tag, options);
+                            }
+                            Err(e) => {
+                                eprintln!("{}", format!("--syslog-tag: {}", e).red());
+                                std::process::exit(1);
+                            }
+                        }
+                    }
+                    "--drop" | "--keep" | "--sample" => {
+                        let value = flag_value(arg, args_iter.next());
+                        let result = match arg.as_str() {
+                            "--drop" => filter.add(Action::Drop, value),
+                            "--keep" => filter.add(Action::Keep, value),
+                            _ => filter.add_sample(value),
+                        };
+                        if let Err(e) = result {
+                            eprintln!("{}", format!("{}: {}", arg, e).red());
+                            std::process::exit(1);
+                        }
+                    }
+                    "--truncate-ips" => {
+                        anonymizer.ips = IpMode::Truncate;
+                    }
+                    "--hmac-ips" => {
+                        let key = secret_value(arg, args_iter.next());
+                        anonymizer.ips = IpMode::Hmac(key.into_bytes());
+                    }
+                    "--redact-param" => {
+                        let value = flag_value(arg, args_iter.next());
+                        if let Err(e) = anonymizer.redact_param(value) {
+                            eprintln!("{}", format!("--redact-param: {}", e).red());
+                            std::process::exit(1);
+                        }
+                    }
+                    "--field" => {
+                        let value = flag_value(arg, args_iter.next());
+                        match parse_label(value) {
+                            Ok((key, value)) => {
+                                labels.insert(key, value);
+                            }
+                            Err(e) => {
+                                eprintln!("{}", format!("--field: {}", e).red());
+                                std::process::exit(1);
+                            }
+                        }
+                    }
+                    "--source" => {
+                        let value = flag_value(arg, args_iter.next());
+                        let options = match SourceOptions::parse(value) {
+                            Ok(s) => s,
+                            Err(e) => {
+                                eprintln!("{}", format!("--source: {}", e).red());
+                                std::process::exit(1);
+                            }
+                        };
+                        // the path is taken the same way as when it's given on its own
+                        let path = value.split(',').next().unwrap_or_default().trim();
+                        if options.is_syslog() {
+                            // only matched against the messages of --syslog
+                        } else if path == "-" {
+                            if !pipes.contains(&Pipe::Stdin) {
+                                pipes.push(Pipe::Stdin);
+                            }
+                        } else if is_fifo(Path::new(path)) {
+                            pipes.push(Pipe::Fifo(PathBuf::from(path)));
+                        } else if is_glob(path) && !Path::new(path).exists() {
+                            nginx_globs.push(path.to_string());
+                        } else {
+                            new_locations.push(path);
+                        }
+                        sources.push(options);
+                    }
+                    "--workers" => {
+                        pipeline.workers = count_value(arg, args_iter.next());
+                    }
+                    "--queue-size" => {
+                        pipeline.queue_size = count_value(arg, args_iter.next());
+                    }
+                    "--concurrency" => {
+                        pipeline.concurrency = count_value(arg, args_iter.next());
+                    }
+                    "--rollover" => {
+                        let value = flag_value(arg, args_iter.next());
+                        match Rollover::parse(value) {
+                            Some(r) => index_options.rollover = Some(r),
+                            None => {
+                                eprintln!(
+                                    "{}",
+                                    format!("--rollover expects daily or monthly, got {}", value)
+                                        .red()
+                                );
+                                std::process::exit(1);
+                            }
+                        }
+                    }
+                    _ => {
+                        eprintln!("{}", format!("Unknown flag: {}", arg).red());
+                        std::process::exit(1);
+                    }
+                }
+            } else if arg == "migrate" {
+                command = Command::Migrate { dry_run: false };
+            } else if arg == "-" {
+                // reading the lines from stdin
+                if !pipes.contains(&Pipe::Stdin) {
+                    pipes.push(Pipe::Stdin);
+                }
+            } else if is_fifo(Path::new(arg)) {
+                // a named pipe is read like stdin, checking it would eat the lines
+                pipes.push(Pipe::Fifo(PathBuf::from(arg)));
+            } else if is_glob(arg) && !Path::new(arg).exists() {
+                // a quoted pattern, the files it matches are tailed as they come and go
+                nginx_globs.push(arg.to_string());
+            } else if Path::new(arg).is_dir() {
                 // specifying a directory sets it to the archiving directory
                 new_archiving.push(arg);
             } else if Path::new(arg).exists() {
                 if let Ok(_) = Cert::new(PathBuf::from(arg)) {
-                    cert_path = Some(PathBuf::from(arg));
+                    connection.cert_path = Some(PathBuf::from(arg));
                 } else {
                     // specifying a file sets the file we are reading from
                     new_locations.push(arg);
                 }
+            } else if output::is_file_url(arg) {
+                // specifying a file:// url writes the documents to a file as well
+                new_files.push(arg);
             } else if server::is_url(String::from(arg)) {
                 // specifying the url sets the elasticsearch url
                 new_servers.push(arg);
@@ -60,6 +384,62 @@ impl Config {
             }
         }
 
+        if let Command::Migrate { .. } = command {
+            command = Command::Migrate { dry_run };
+        } else if dry_run {
+            eprintln!("{}", "--dry-run only applies to migrate".red());
+            std::process::exit(1);
+        }
+        let migrating = command != Command::Run;
+
+        // Reading an answer from stdin would swallow a log line, or wait on nothing
+        if index_options.prompt == Prompt::Ask
+            && (pipes.contains(&Pipe::Stdin) || !stdin().is_terminal())
+        {
+            index_options.prompt = Prompt::NoTerminal;
+        }
+
+        if index_options.data_stream && index_options.rollover.is_some() {
+            eprintln!(
+                "{}",
+                "--data-stream and --rollover can't be combined, data streams roll over by themselves"
+                    .red()
+            );
+            std::process::exit(1);
+        }
+
+        if let Some(cert) = client_cert {
+            match ClientIdentity::new(cert.clone(), client_key, client_cert_password) {
+                Ok(identity) => connection.client_identity = Some(identity),
+                Err(e) => {
+                    eprintln!(
+                        "{}",
+                        format!("Unable to load client certificate {:?}: {}", cert, e).red()
+                    );
+                    std::process::exit(1);
+                }
+            }
+        } else if client_key.is_some() {
+            eprintln!("{}", "--client-key requires --client-cert".red());
+            std::process::exit(1);
+        }
+
+        if connection.insecure {
+            println!(
+                "{}",
+                "WARNING: --insecure is set, server certificates are NOT verified!"
+                    .red()
+                    .bold()
+            );
+            println!(
+                "{}",
+                "WARNING: Anyone in between can read and change the traffic, including credentials."
+                    .red()
+                    .bold()
+            );
+            println!();
+        }
+
         new_locations.reverse();
         locations.reverse();
         locations.extend(new_locations);
@@ -77,40 +457,255 @@ impl Config {
 
         // Choosing a file path
         let mut nginx_sources: Vec<PathBuf> = vec![];
-        println!(
-            "Checking file nginx_sources ({}: {}, {}: {}, {}: {}): ",
-            "✓".green(),
-            "chosen".green(),
-            "-".yellow(),
-            "skip".yellow(),
-            "X".red(),
-            "Not found".red()
-        );
+        if !migrating {
+            println!(
+                "Checking file nginx_sources ({}: {}, {}: {}, {}: {}): ",
+                "✓".green(),
+                "chosen".green(),
+                "-".yellow(),
+                "skip".yellow(),
+                "X".red(),
+                "Not found".red()
+            );
 
-        for loc in &locations {
-            print!("[ ] {} ...", loc);
-            stdout().flush().unwrap();
-            if valid_log(loc) {
-                print!("{}", "\r[✓]\n".green());
-                nginx_sources.push(PathBuf::from(loc));
-            } else {
-                print!("{}", "\r[X]\n".red());
+            for loc in &locations {
+                print!("[ ] {} ...", loc);
+                stdout().flush().unwrap();
+                if valid_log(loc, &json_fields, index_options.prompt) {
+                    print!("{}", "\r[✓]\n".green());
+                    nginx_sources.push(PathBuf::from(loc));
+                } else {
+                    print!("{}", "\r[X]\n".red());
+                }
+            }
+            for pattern in &nginx_globs {
+                // Matches aren't checked like the files above, new vhosts start out empty
+                let files: Vec<PathBuf> = glob_files(pattern)
+                    .into_iter()
+                    .filter(|f| !nginx_sources.contains(f))
+                    .collect();
+                println!(
+                    "{} {} ({} files, checked for new ones every {} seconds)",
+                    "[✓]".green(),
+                    pattern,
+                    files.len(),
+                    GLOB_INTERVAL.as_secs()
+                );
+                for file in files {
+                    match std::fs::File::open(&file) {
+                        Ok(_) => {
+                            println!("    {} {}", "[✓]".green(), file.display());
+                            nginx_sources.push(file);
+                        }
+                        Err(e) => println!("    {} {}: {}", "[X]".red(), file.display(), e),
+                    }
+                }
+            }
+            for pipe in &pipes {
+                println!("{} {} (read until it ends)", "[✓]".green(), pipe);
+            }
+            if nginx_sources.is_empty()
+                && nginx_globs.is_empty()
+                && pipes.is_empty()
+                && syslog.is_empty()
+            {
+                eprintln!("{}", "No log file found to log data from");
+            }
+            println!();
+        }
+
+        if !syslog.is_empty() && !migrating {
+            println!("Syslog:");
+            for listener in &syslog {
+                println!("{} {}", "[✓]".green(), listener);
             }
+            let mut tags: Vec<_> = syslog_tags.iter().collect();
+            tags.sort_by_key(|(tag, _)| *tag);
+            for (tag, options) in tags {
+                println!("{} tag {} {}", "[✓]".green(), tag, options);
+            }
+            println!();
+        } else if !syslog_tags.is_empty() && !migrating {
+            eprintln!("{}", "--syslog-tag requires --syslog".red());
+            std::process::exit(1);
         }
-        if nginx_sources.is_empty() {
-            eprintln!("{}", "No log file found to log data from");
+
+        if !custom_json_fields.is_empty() && !migrating {
+            println!("JSON fields:");
+            for field in &custom_json_fields {
+                println!("{} {}", "[✓]".green(), field);
+            }
+            println!();
         }
-        println!();
+
+        if (!labels.is_empty() || !sources.is_empty()) && !migrating {
+            println!("Source fields:");
+            if !labels.is_empty() {
+                println!("{} all ({})", "[✓]".green(), format_labels(&labels));
+            }
+            for source in &sources {
+                println!("{} {}", "[✓]".green(), source);
+            }
+            println!();
+        }
+        for source in &mut sources {
+            source.merge_labels(&labels);
+        }
+        let labels: Labels = Arc::new(labels);
+
+        if !filter.is_empty() && !migrating {
+            println!("Filters:");
+            for rule in filter.rules() {
+                println!("{} {}", "[✓]".green(), rule);
+            }
+            println!();
+        }
+        let filter = Arc::new(filter);
+
+        if anonymizer.is_enabled() && !migrating {
+            println!("Anonymization:");
+            match anonymizer.ips {
+                IpMode::Keep => {}
+                IpMode::Truncate => {
+                    println!("{} ip addresses truncated to /24 and /48", "[✓]".green())
+                }
+                IpMode::Hmac(_) => println!(
+                    "{} ip addresses pseudonymised with HMAC-SHA256",
+                    "[✓]".green()
+                ),
+            }
+            if !anonymizer.params().is_empty() {
+                println!(
+                    "{} redacting query parameters {}",
+                    "[✓]".green(),
+                    anonymizer.params().join(", ")
+                );
+            }
+            println!();
+        }
+        let anonymizer = Arc::new(anonymizer);
 
         // Bulk size
         println!("Bulk size:");
         println!("{} {}", "[✓]".green(), bulk_size,);
 
-        // Choosing a server
-        let mut server: Option<Server> = None;
+        if !migrating {
+            println!();
+            println!("Pipeline:");
+            println!(
+                "{} {} parser worker(s), up to {} lines queued, {} bulk request(s) at once per output",
+                "[✓]".green(),
+                pipeline.workers,
+                pipeline.queue_size,
+                pipeline.concurrency
+            );
+        }
+
+        if !migrating {
+            println!();
+            println!("Document ids:");
+            if id_strategy == IdStrategy::TimeIp {
+                println!(
+                    "{} {} {}",
+                    "[-]".yellow(),
+                    id_strategy,
+                    "(requests from the same ip in the same second overwrite each other)".yellow()
+                );
+            } else {
+                println!("{} {}", "[✓]".green(), id_strategy);
+            }
+        }
+
+        // Enrichment
+        let mut enricher = Enricher {
+            trusted_proxies,
+            ..Default::default()
+        };
+        if !migrating {
+            println!();
+            println!("Trusted proxies:");
+            println!("{} {}", "[✓]".green(), enricher.trusted_proxies);
+        }
+        if parse_user_agents && !migrating {
+            println!();
+            println!("User agent parsing:");
+            let parser = match &user_agent_regexes {
+                Some(path) => UserAgentParser::from_file(path),
+                None => UserAgentParser::embedded(),
+            };
+            match parser {
+                Ok(p) => {
+                    let source = match &user_agent_regexes {
+                        Some(path) => format!("{:?}", path),
+                        None => "embedded".to_string(),
+                    };
+                    println!("{} {} ({} regexes)", "[✓]".green(), source, p.regex_count());
+                    if p.skipped > 0 {
+                        println!(
+                            "{}",
+                            format!("[-] {} regexes are not supported and skipped", p.skipped)
+                                .yellow()
+                        );
+                    }
+                    enricher.user_agents = Some(p);
+                }
+                Err(e) => {
+                    eprintln!(
+                        "{}",
+                        format!("Unable to load the user agent regexes: {}", e).red()
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
+        if (geoip_city.is_some() || geoip_asn.is_some()) && !migrating {
+            println!();
+            println!("GeoIP:");
+            for (path, db) in [
+                (&geoip_city, &mut enricher.geoip.city),
+                (&geoip_asn, &mut enricher.geoip.asn),
+            ] {
+                let Some(path) = path else { continue };
+                match GeoDatabase::open(path) {
+                    Ok(d) => {
+                        println!("{} {:?} ({})", "[✓]".green(), path, d.database_type());
+                        *db = Some(d);
+                    }
+                    Err(e) => {
+                        eprintln!("{}", format!("{:#}", e).red());
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+        let enricher = Arc::new(enricher);
+
+        // Certificates
+        if let Some(cert) = connection.cert_path.clone().and_then(|p| Cert::new(p).ok()) {
+            println!();
+            println!("Certificates:");
+            for info in cert.info {
+                let line = format!("{} (expires {})", info.subject, info.not_after);
+                if info.days_left < 0 {
+                    println!("{} {} {}", "[X]".red(), line, "EXPIRED".red());
+                } else if info.days_left < 30 {
+                    println!(
+                        "{} {} {}",
+                        "[-]".yellow(),
+                        line,
+                        format!("expires in {} days", info.days_left).yellow()
+                    );
+                } else {
+                    println!("{} {}", "[✓]".green(), line);
+                }
+            }
+        }
+
+        // Choosing the outputs
+        let mut outputs: Vec<Arc<Output>> = vec![];
         println!();
         println!(
-            "Checking Servers ({}: {}, {}: {}, {}: {}): ",
+            "Checking outputs ({}: {}, {}: {}, {}: {}): ",
             "✓".green(),
             "chosen".green(),
             "-".yellow(),
@@ -118,44 +713,142 @@ impl Config {
             "X".red(),
             "Failed".red()
         );
-        tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .build()
-            .unwrap()
-            .block_on(async {
-                for ser in servers {
-                    let ser = Server::new(ser, cert_path.clone());
-                    print!("[ ] {} ...", ser);
+        // The indices picked by syslog tags and sources, next to the one in the url
+        let mut routed: Vec<String> = syslog_tags
+            .values()
+            .filter_map(|t| t.index.clone())
+            .chain(sources.iter().filter_map(|s| s.index.clone()))
+            .collect();
+        routed.sort();
+        routed.dedup();
+        // The default servers are only a fallback in case none of the provided ones work
+        let provided = servers.len();
+        let mut failed_servers: Vec<String> = vec![];
+        for (i, ser) in servers.iter().chain(DEFAULT_SERVERS.iter()).enumerate() {
+            let output_bulk_size = output::bulk_size_from_url(ser).unwrap_or(bulk_size);
+            let ser = Server::new(ser, &connection, &index_options);
+            print!("[ ] {} ...", ser);
+            stdout().flush().unwrap();
+            if i >= provided && !outputs.is_empty() {
+                print!("{}", " (Not bothering checking)".yellow());
+                print!("{}", "\r[-]\n".yellow());
+            } else if let Err(e) = match migrating {
+                // The mapping is what's getting fixed, so it isn't checked
+                true => ser.is_es().await,
+                false => ser.db_exists().await,
+            } {
+                print!("{e}");
+                print!("{}", "\r[X]\n".red());
+                if i < provided {
+                    failed_servers.push(ser.to_string());
+                }
+            } else {
+                if ser.cert.is_some() {
+                    if let Some(cp) = connection
+                        .cert_path
+                        .as_ref()
+                        .and_then(|p| p.to_str())
+                        .map(|s| s.to_string())
+                    {
+                        print!(" (cert: {})", cp);
+                    }
+                }
+                if connection.client_identity.is_some() {
+                    print!(" (client cert)");
+                }
+                print!(" ({})", ser.auth);
+                if let Some(r) = index_options.rollover {
+                    print!(" ({} indices)", r);
+                }
+                if index_options.data_stream {
+                    print!(" (data stream)");
+                }
+                if index_options.ilm {
+                    print!(" (ilm)");
+                }
+                print!(" (bulk size: {})", output_bulk_size);
+                print!("{}", "\r[✓]\n".green());
+                // Asking first, the same as for the index in the url
+                for index in routed.iter().filter(|i| !migrating && *i != ser.index()) {
+                    print!("    [ ] {} ...", index);
                     stdout().flush().unwrap();
-                    if server.is_some() {
-                        print!("{}", " (Not bothering checking)".yellow());
-                        print!("{}", "\r[-]\n".yellow());
-                    } else if let Err(e) = ser.db_exists().await {
+                    if let Err(e) = ser.with_index(index).db_exists().await {
                         print!("{e}");
-                        print!("{}", "\r[X]\n".red());
-                    } else {
-                        if ser.cert.is_some() {
-                            if let Some(cp) = cert_path
-                                .as_ref()
-                                .and_then(|p| p.to_str())
-                                .map(|s| s.to_string())
-                            {
-                                print!(" (cert: {})", cp);
-                            }
-                        }
-                        print!("{}", "\r[✓]\n".green());
-                        server = Some(ser.clone());
+                        print!("{}", "\r    [X]\n".red());
+                        std::process::exit(1);
                     }
+                    print!("{}", "\r    [✓]\n".green());
                 }
-                println!();
-            });
+                outputs.push(Arc::new(Output::new(
+                    Destination::Elasticsearch(Box::new(ser)),
+                    output_bulk_size,
+                )));
+            }
+        }
 
-        let server: Server = if let Some(s) = server {
-            s
-        } else {
+        let mut failed_files: Vec<&str> = vec![];
+        for file in new_files {
+            let output_bulk_size = output::bulk_size_from_url(file).unwrap_or(bulk_size);
+            print!("[ ] {} ...", file);
+            stdout().flush().unwrap();
+            match Output::from_file_url(file, output_bulk_size) {
+                Ok(o) => {
+                    print!(" (bulk size: {})", output_bulk_size);
+                    print!("{}", "\r[✓]\n".green());
+                    outputs.push(Arc::new(o));
+                }
+                Err(e) => {
+                    print!("{e}");
+                    print!("{}", "\r[X]\n".red());
+                    failed_files.push(file);
+                }
+            }
+        }
+        println!();
+
+        // Running with fewer outputs than given would go unnoticed until the data is missing.
+        // Only when none of the servers work is the default one used instead
+        if !failed_files.is_empty()
+            || (!failed_servers.is_empty() && failed_servers.len() < provided)
+        {
+            let failed: Vec<&str> = failed_servers
+                .iter()
+                .map(String::as_str)
+                .chain(failed_files)
+                .collect();
+            eprintln!(
+                "{}",
+                format!("Not all outputs passed the checks: {}", failed.join(", ")).red()
+            );
+            std::process::exit(1);
+        }
+
+        if !outputs.iter().any(|o| o.server().is_some()) {
             println!("{}", "No Elasticsearch server specified".red());
             std::process::exit(1);
-        };
+        }
+
+        if migrating {
+            return Self {
+                command,
+                nginx_sources,
+                nginx_globs,
+                pipes,
+                outputs,
+                archive_folder: None,
+                archive_file_prefix,
+                enricher,
+                filter,
+                anonymizer,
+                id_strategy,
+                json_fields,
+                syslog,
+                syslog_tags,
+                labels,
+                sources,
+                pipeline,
+            };
+        }
 
         // Choosing an archiving path
         let mut archive_folder: Option<PathBuf> = None;
@@ -198,7 +891,12 @@ impl Config {
         }
         println!();
 
-        if nginx_sources.is_empty() && archive_folder.is_none() {
+        if nginx_sources.is_empty()
+            && nginx_globs.is_empty()
+            && pipes.is_empty()
+            && syslog.is_empty()
+            && archive_folder.is_none()
+        {
             eprintln!(
                 "The application serves no purpose without any nginx logs and no archive location"
             );
@@ -206,11 +904,38 @@ impl Config {
         }
 
         Self {
+            command,
             nginx_sources,
-            server,
+            nginx_globs,
+            pipes,
+            outputs,
             archive_folder,
             archive_file_prefix,
-            bulk_size,
+            enricher,
+            filter,
+            anonymizer,
+            id_strategy,
+            json_fields,
+            syslog,
+            syslog_tags,
+            labels,
+            sources,
+            pipeline,
         }
     }
+
+    /// Sets where the document came from, with the fields and index given for that source.
+    /// The fields of the source take priority over the ones for all sources
+    pub fn label(&self, document: &mut Document, source: &str) {
+        let labels = match self.sources.iter().find(|s| s.matches(source)) {
+            Some(options) => {
+                if options.index.is_some() {
+                    document.set_index(options.index.clone());
+                }
+                options.all_labels()
+            }
+            None => &self.labels,
+        };
+        document.set_source(source.to_string(), labels.clone());
+    }
 }
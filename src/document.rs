@@ -0,0 +1,115 @@
+use anyhow::{Error, Result};
+use serde_derive::Serialize;
+use std::fmt;
+use std::path::Path;
+
+use crate::errorlog::{is_error_line, ErrorLog};
+use crate::json::JsonFields;
+use crate::logger::{IdStrategy, Logger};
+use crate::source::Labels;
+
+/// Which parser a line goes through
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Format {
+    /// Error log lines are recognized by their timestamp, JSON lines by their `{`
+    #[default]
+    Auto,
+    /// Access log lines in the combined_realip format
+    Access,
+    /// Access log lines of `log_format ... escape=json`
+    Json,
+    Error,
+}
+
+impl Format {
+    pub fn parse(str: &str) -> Option<Self> {
+        match str {
+            "auto" => Some(Format::Auto),
+            "access" => Some(Format::Access),
+            "json" => Some(Format::Json),
+            "error" => Some(Format::Error),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Auto => write!(f, "auto"),
+            Format::Access => write!(f, "access"),
+            Format::Json => write!(f, "json"),
+            Format::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A parsed line, from either the access log or the error log
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "log", rename_all = "lowercase")]
+pub enum Document {
+    Access(Box<Logger>),
+    Error(Box<ErrorLog>),
+}
+
+impl Document {
+    /// Error log lines are recognized by their timestamp, everything else is an access log line
+    pub fn parse(line: &String, json_fields: &JsonFields) -> Result<Self, Error> {
+        Document::parse_as(line, Format::Auto, json_fields)
+    }
+
+    /// Parses the line as the given format, guessing it with `Format::Auto`
+    pub fn parse_as(
+        line: &String,
+        format: Format,
+        json_fields: &JsonFields,
+    ) -> Result<Self, Error> {
+        let access = |logger: Logger| Ok(Document::Access(Box::new(logger)));
+        match format {
+            Format::Auto if is_error_line(line) => {
+                Ok(Document::Error(Box::new(ErrorLog::from_line(line)?)))
+            }
+            Format::Auto => access(Logger::parse(line, json_fields)?),
+            Format::Access => access(Logger::from_line(line)?),
+            Format::Json => access(Logger::from_json(line, json_fields)?),
+            Format::Error => Ok(Document::Error(Box::new(ErrorLog::from_line(line)?))),
+        }
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Document::Access(l) => l.id(),
+            Document::Error(e) => e.id(),
+        }
+    }
+
+    /// The index the document goes into, when it's not the one in the url
+    pub fn index(&self) -> Option<&str> {
+        match self {
+            Document::Access(l) => l.index(),
+            Document::Error(e) => e.index(),
+        }
+    }
+
+    pub fn set_index(&mut self, index: Option<String>) {
+        match self {
+            Document::Access(l) => l.set_index(index),
+            Document::Error(e) => e.set_index(index),
+        }
+    }
+
+    /// Where the document came from, and the static fields that go with it
+    pub fn set_source(&mut self, source: String, labels: Labels) {
+        match self {
+            Document::Access(l) => l.set_source(source, labels),
+            Document::Error(e) => e.set_source(source, labels),
+        }
+    }
+
+    pub fn assign_id(&mut self, strategy: IdStrategy, line: &str, source: &Path, offset: u64) {
+        match self {
+            Document::Access(l) => l.assign_id(strategy, line, source, offset),
+            Document::Error(e) => e.assign_id(strategy, line, source, offset),
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use crate::geoip::GeoIp;
+use crate::logger::Logger;
+use crate::proxy::TrustedProxies;
+use crate::useragent::UserAgentParser;
+
+/// Optional steps adding information to a parsed line before it's sent to the outputs
+#[derive(Default)]
+pub struct Enricher {
+    /// Proxies skipped when picking the client out of the X-Forwarded-For chain
+    pub trusted_proxies: TrustedProxies,
+    /// Splits the user agent into browser, OS, device and bot flag
+    pub user_agents: Option<UserAgentParser>,
+    /// Adds location and ASN of the ip addresses
+    pub geoip: GeoIp,
+}
+
+impl Enricher {
+    pub fn enrich(&self, logger: &mut Logger) {
+        logger.resolve_client_ip(&self.trusted_proxies);
+        if self.geoip.is_enabled() {
+            logger.set_geo(self.geoip.lookup(logger.client_ip()));
+        }
+        if let Some(parser) = &self.user_agents {
+            if let Some(ua) = logger.user_agent() {
+                let agent = parser.parse(ua);
+                logger.set_agent(agent);
+            }
+        }
+    }
+}
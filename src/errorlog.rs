@@ -0,0 +1,283 @@
+use anyhow::{bail, Context, Error, Result};
+use chrono::{Local, NaiveDateTime, TimeZone};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::anonymize::Anonymizer;
+use crate::logger::{line_id, IdStrategy};
+use crate::server::Server;
+use crate::source::Labels;
+
+/// A line of nginx's error.log
+/// 2024/01/15 10:23:45 [error] 1234#5678: *91011 upstream timed out (110: Connection timed out) while reading response header from upstream, client: 1.2.3.4, server: example.com, request: "GET /api HTTP/1.1", upstream: "http://10.0.0.1:8080/api", host: "example.com"
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorLog {
+    level: String,
+    pid: u32,
+    tid: u32,
+    /// The connection the error happened on, if any
+    connection: Option<u64>,
+    message: String,
+    client: Option<IpAddr>,
+    server: Option<String>,
+    request: Option<String>,
+    upstream: Option<String>,
+    host: Option<String>,
+    referrer: Option<String>,
+    /// The file, pipe or syslog sender the line came from
+    source: Option<String>,
+    /// Static fields given for the source, or for all of them
+    labels: Labels,
+    time: u64, // In milliseconds
+    /// Not part of the document, it's the _id
+    #[serde(skip)]
+    id: Option<String>,
+    /// Goes into the error index next to this one instead
+    #[serde(skip)]
+    index: Option<String>,
+}
+
+/// The timestamp, level, pid#tid and connection, then the message
+fn line_regex() -> &'static Regex {
+    static LINE: OnceLock<Regex> = OnceLock::new();
+    LINE.get_or_init(|| {
+        Regex::new(
+            r"^(\d{4}/\d{2}/\d{2} \d{2}:\d{2}:\d{2}) \[(\w+)\] (\d+)#(\d+): (?:\*(\d+) )?(.*)$",
+        )
+        .unwrap()
+    })
+}
+
+/// The keys nginx puts after the message, `client: `, `server: `, ...
+fn context_regex() -> &'static Regex {
+    static CONTEXT: OnceLock<Regex> = OnceLock::new();
+    CONTEXT.get_or_init(|| {
+        Regex::new(r"(?:^|, )(client|server|request|subrequest|upstream|host|referrer): ").unwrap()
+    })
+}
+
+/// Just the timestamp and level
+fn start_regex() -> &'static Regex {
+    static START: OnceLock<Regex> = OnceLock::new();
+    START.get_or_init(|| Regex::new(r"^\d{4}/\d{2}/\d{2} \d{2}:\d{2}:\d{2} \[\w+\] ").unwrap())
+}
+
+/// Checks if the line looks like it's from error.log, without parsing all of it
+pub fn is_error_line(line: &str) -> bool {
+    start_regex().is_match(line)
+}
+
+impl ErrorLog {
+    pub fn from_line(line: &str) -> Result<Self, Error> {
+        let cap = line_regex()
+            .captures(line)
+            .context("Regex did not get any captures")?;
+
+        // nginx writes the error log in local time, without the timezone
+        let naive = NaiveDateTime::parse_from_str(&cap[1], "%Y/%m/%d %H:%M:%S")?;
+        let time = Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .context("Time does not exist in the local timezone")?
+            .timestamp_millis() as u64;
+
+        // The context nginx adds at the end, starting with the client
+        let (message, context) = match cap[6].find(", client: ") {
+            Some(i) => (&cap[6][..i], &cap[6][i + 2..]),
+            None => (&cap[6], ""),
+        };
+        let mut error = ErrorLog {
+            level: cap[2].to_string(),
+            pid: cap[3].parse()?,
+            tid: cap[4].parse()?,
+            connection: cap.get(5).and_then(|c| c.as_str().parse().ok()),
+            message: message.to_string(),
+            client: None,
+            server: None,
+            request: None,
+            upstream: None,
+            host: None,
+            referrer: None,
+            source: None,
+            labels: Labels::default(),
+            time,
+            id: None,
+            index: None,
+        };
+        for (key, value) in context_pairs(context) {
+            match key {
+                "client" => error.client = value.parse().ok(),
+                "server" => error.server = Some(value.to_string()),
+                "request" => error.request = Some(value.to_string()),
+                "upstream" => error.upstream = Some(value.to_string()),
+                "host" => error.host = Some(value.to_string()),
+                "referrer" => error.referrer = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Ok(error)
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    pub fn index(&self) -> Option<&str> {
+        self.index.as_deref()
+    }
+
+    pub fn set_index(&mut self, index: Option<String>) {
+        self.index = index;
+    }
+
+    pub fn anonymize(&mut self, anonymizer: &Anonymizer) {
+        self.client = self.client.map(|ip| anonymizer.ip(ip));
+        self.request = self.request.as_deref().map(|r| anonymizer.redact(r));
+        self.referrer = self.referrer.as_deref().map(|r| anonymizer.redact(r));
+        self.upstream = self.upstream.as_deref().map(|u| anonymizer.redact(u));
+    }
+
+    pub fn set_source(&mut self, source: String, labels: Labels) {
+        self.source = Some(source);
+        self.labels = labels;
+    }
+
+    /// Same as for access log lines, except there's no ip to go by for time-ip
+    pub fn assign_id(&mut self, strategy: IdStrategy, line: &str, source: &Path, offset: u64) {
+        self.id = match strategy {
+            IdStrategy::Auto => None,
+            IdStrategy::Line | IdStrategy::TimeIp => Some(line_id(line, source, offset)),
+        };
+    }
+
+    /// Mappings for the error index
+    pub fn index_body() -> Value {
+        let keyword = json!({ "type": "keyword", "ignore_above": 256 });
+        let text = json!({
+            "type": "text",
+            "fields": { "keyword": { "type": "keyword", "ignore_above": 256 } }
+        });
+        json!({
+            "mappings": {
+                "dynamic": "false",
+                "properties": {
+                    "level": keyword,
+                    "pid": { "type": "integer" },
+                    "tid": { "type": "integer" },
+                    "connection": { "type": "long" },
+                    "message": text,
+                    "client": { "type": "ip" },
+                    "server": keyword,
+                    "request": text,
+                    "upstream": keyword,
+                    "host": keyword,
+                    "referrer": keyword,
+                    "source": keyword,
+                    "labels": { "type": "flattened" },
+                    "time": {
+                        "type": "date",
+                        "format": "strict_date_optional_time||epoch_millis"
+                    }
+                }
+            }
+        })
+    }
+
+    /// Creates the error index next to the access log index, unless it exists
+    pub async fn ensure_index(server: &Server) -> Result<(), Error> {
+        let url = format!("{}/{}", server.get_host(), server.error_index());
+        if server.http().head(&url).send().await?.status() == reqwest::StatusCode::OK {
+            return Ok(());
+        }
+        let res: Value = server
+            .http()
+            .put(&url)
+            .json(&ErrorLog::index_body())
+            .send()
+            .await?
+            .json()
+            .await?;
+        if res["acknowledged"].as_bool() != Some(true) {
+            bail!("Failed to create {}: {}", server.error_index(), res);
+        }
+        println!("  Created index {}", server.error_index());
+        Ok(())
+    }
+}
+
+/// Splits `client: 1.2.3.4, server: example.com, request: "GET / HTTP/1.1"` into its pairs.
+/// The values run until the next known key, since a request can contain ", "
+fn context_pairs(context: &str) -> Vec<(&str, &str)> {
+    let keys: Vec<_> = context_regex().captures_iter(context).collect();
+    keys.iter()
+        .enumerate()
+        .map(|(i, cap)| {
+            let end = keys
+                .get(i + 1)
+                .map(|next| next.get(0).unwrap().start())
+                .unwrap_or(context.len());
+            let value = context[cap.get(0).unwrap().end()..end].trim();
+            (cap.get(1).unwrap().as_str(), value.trim_matches('"'))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anonymize::IpMode;
+
+    #[test]
+    fn error_lines() {
+        let line = r#"2024/01/15 10:23:45 [error] 1234#5678: *91011 open() "/srv/a, b" failed, client: 1.2.3.4, server: example.com, request: "GET /a, b HTTP/1.1", host: "example.com", referrer: "https://example.com/""#;
+        assert!(is_error_line(line));
+        let error = ErrorLog::from_line(line).unwrap();
+        assert_eq!(error.level, "error");
+        assert_eq!(
+            (error.pid, error.tid, error.connection),
+            (1234, 5678, Some(91011))
+        );
+        assert_eq!(error.message, r#"open() "/srv/a, b" failed"#);
+        assert_eq!(error.client, Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(error.server.as_deref(), Some("example.com"));
+        assert_eq!(error.request.as_deref(), Some("GET /a, b HTTP/1.1"));
+        assert_eq!(error.host.as_deref(), Some("example.com"));
+        assert_eq!(error.referrer.as_deref(), Some("https://example.com/"));
+        assert_eq!(error.upstream, None);
+
+        // Startup messages have no connection and no context
+        let error = ErrorLog::from_line("2024/01/15 10:23:45 [notice] 1#1: signal process started")
+            .unwrap();
+        assert_eq!(error.connection, None);
+        assert_eq!(error.message, "signal process started");
+        assert_eq!(error.client, None);
+
+        assert!(!is_error_line(
+            r#"1.2.3.4 - - [17/Sep/2022:23:39:19 +0200] "a.com""#
+        ));
+        assert!(ErrorLog::from_line("2024/01/15 [error] nope").is_err());
+    }
+
+    #[test]
+    fn anonymized_context() {
+        let line = r#"2024/01/15 10:23:45 [error] 1234#5678: *91011 upstream timed out, client: 1.2.3.4, server: example.com, request: "GET /api?token=1 HTTP/1.1", upstream: "http://10.0.0.1:8080/api?token=1", host: "example.com""#;
+        let mut error = ErrorLog::from_line(line).unwrap();
+        let mut anonymizer = Anonymizer::default();
+        anonymizer.ips = IpMode::Truncate;
+        anonymizer.redact_param("token").unwrap();
+        error.anonymize(&anonymizer);
+        assert_eq!(error.client, Some("1.2.3.0".parse().unwrap()));
+        assert_eq!(
+            error.request.as_deref(),
+            Some("GET /api?token=REDACTED HTTP/1.1")
+        );
+        assert_eq!(
+            error.upstream.as_deref(),
+            Some("http://10.0.0.1:8080/api?token=REDACTED")
+        );
+    }
+}
@@ -0,0 +1,318 @@
+use anyhow::{bail, Context, Error, Result};
+use ipnet::IpNet;
+use regex::Regex;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::logger::Logger;
+
+/// The fields a condition can be on
+const KEYS: [&str; 5] = ["status", "path", "user_agent", "ip", "host"];
+
+/// One part of a rule, like `status=200-299` or `path=~^/health`
+enum Condition {
+    Status(u16, u16),
+    Path(Regex),
+    UserAgent(Regex),
+    Ip(IpNet),
+    Host(String),
+}
+
+impl Condition {
+    fn parse(spec: &str) -> Result<Self, Error> {
+        let (key, value) = spec
+            .split_once('=')
+            .with_context(|| format!("Expected key=value, got {}", spec))?;
+        let regex = |value: &str| -> Result<Regex, Error> {
+            let pattern = value
+                .strip_prefix('~')
+                .with_context(|| format!("{} expects a regex, as {}=~pattern", key, key))?;
+            Regex::new(pattern).with_context(|| format!("Invalid regex {}", pattern))
+        };
+        Ok(match key {
+            "status" => {
+                let (from, to) = value.split_once('-').unwrap_or((value, value));
+                let (from, to) = (parse_status(from)?, parse_status(to)?);
+                if from > to {
+                    bail!("Empty status range {}", value);
+                }
+                Condition::Status(from, to)
+            }
+            "path" => Condition::Path(regex(value)?),
+            "user_agent" => Condition::UserAgent(regex(value)?),
+            "ip" => Condition::Ip(match value.parse::<IpNet>() {
+                Ok(n) => n,
+                Err(_) => IpNet::from(
+                    value
+                        .parse::<IpAddr>()
+                        .with_context(|| format!("{} is not a CIDR or ip address", value))?,
+                ),
+            }),
+            "host" => Condition::Host(value.to_lowercase()),
+            _ => bail!(
+                "Unknown field {}, expected status, path, user_agent, ip or host",
+                key
+            ),
+        })
+    }
+
+    fn matches(&self, logger: &Logger) -> bool {
+        match self {
+            Condition::Status(from, to) => (*from..=*to).contains(&logger.status_code()),
+            Condition::Path(re) => re.is_match(logger.path()),
+            Condition::UserAgent(re) => logger.user_agent().is_some_and(|ua| re.is_match(ua)),
+            Condition::Ip(net) => net.contains(&logger.client_ip()),
+            Condition::Host(host) => logger.host().is_some_and(|h| h.eq_ignore_ascii_case(host)),
+        }
+    }
+}
+
+fn parse_status(str: &str) -> Result<u16, Error> {
+    str.trim()
+        .parse()
+        .with_context(|| format!("{} is not a status code", str))
+}
+
+/// Splits only on the commas that start another condition,
+/// so the ones inside a regex (`path=~^/a{1,3}$`) stay
+fn split_conditions(spec: &str) -> Vec<&str> {
+    let mut conditions = vec![];
+    let mut start = 0;
+    for (i, _) in spec.match_indices(',') {
+        let next = &spec[i + 1..];
+        let starts_condition = KEYS.iter().any(|key| {
+            next.strip_prefix(key)
+                .is_some_and(|rest| rest.starts_with('='))
+        });
+        if starts_condition {
+            conditions.push(&spec[start..i]);
+            start = i + 1;
+        }
+    }
+    conditions.push(&spec[start..]);
+    conditions
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+    Drop,
+    Keep,
+    /// Keep 1 in N
+    Sample(u64),
+}
+
+/// Conditions that all have to match, and what to do with the line when they do
+pub struct Rule {
+    action: Action,
+    conditions: Vec<Condition>,
+    /// As given on the command line
+    spec: String,
+    matched: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Rule {
+    fn matches(&self, logger: &Logger) -> bool {
+        self.conditions.iter().all(|c| c.matches(logger))
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.action {
+            Action::Drop => write!(f, "drop {}", self.spec),
+            Action::Keep => write!(f, "keep {}", self.spec),
+            Action::Sample(n) => write!(f, "sample 1/{} {}", n, self.spec),
+        }
+    }
+}
+
+/// Decides which access log lines are indexed, after they're parsed
+#[derive(Default)]
+pub struct Filter {
+    rules: Vec<Rule>,
+    /// Lines that didn't match any of the keep rules
+    not_kept: AtomicU64,
+}
+
+impl Filter {
+    /// Adds a rule of comma separated conditions, like `status=200-299,path=~^/health$`
+    pub fn add(&mut self, action: Action, spec: &str) -> Result<(), Error> {
+        let conditions = split_conditions(spec)
+            .into_iter()
+            .map(Condition::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        self.rules.push(Rule {
+            action,
+            conditions,
+            spec: spec.to_string(),
+            matched: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        });
+        Ok(())
+    }
+
+    /// Adds a sample rule, given as `N:conditions`
+    pub fn add_sample(&mut self, spec: &str) -> Result<(), Error> {
+        let (n, conditions) = spec
+            .split_once(':')
+            .with_context(|| format!("Expected N:conditions, got {}", spec))?;
+        let n: u64 = n
+            .parse()
+            .ok()
+            .filter(|n| *n > 0)
+            .with_context(|| format!("{} is not a positive number", n))?;
+        self.add(Action::Sample(n), conditions)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn not_kept(&self) -> u64 {
+        self.not_kept.load(Ordering::Relaxed)
+    }
+
+    /// With keep rules, only the lines matching one of them get through.
+    /// Then the first drop or sample rule that matches decides
+    pub fn allows(&self, logger: &Logger) -> bool {
+        let mut keeps = self
+            .rules
+            .iter()
+            .filter(|r| r.action == Action::Keep)
+            .peekable();
+        if keeps.peek().is_some() {
+            match keeps.find(|r| r.matches(logger)) {
+                Some(rule) => {
+                    rule.matched.fetch_add(1, Ordering::Relaxed);
+                }
+                None => {
+                    self.not_kept.fetch_add(1, Ordering::Relaxed);
+                    return false;
+                }
+            }
+        }
+
+        let rule = self
+            .rules
+            .iter()
+            .filter(|r| r.action != Action::Keep)
+            .find(|r| r.matches(logger));
+        let Some(rule) = rule else {
+            return true;
+        };
+        let matched = rule.matched.fetch_add(1, Ordering::Relaxed);
+        let allowed = match rule.action {
+            Action::Sample(n) => matched % n == 0,
+            _ => false,
+        };
+        if !allowed {
+            rule.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    /// How many lines every rule dropped so far
+    pub fn report(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .rules
+            .iter()
+            .filter(|r| r.action != Action::Keep)
+            .map(|r| format!("{}: {} dropped", r, r.dropped()))
+            .collect();
+        if self.rules.iter().any(|r| r.action == Action::Keep) {
+            lines.push(format!(
+                "not matching a keep rule: {} dropped",
+                self.not_kept()
+            ));
+        }
+        lines
+    }
+
+    /// All the lines dropped so far
+    pub fn total_dropped(&self) -> u64 {
+        self.not_kept() + self.rules.iter().map(Rule::dropped).sum::<u64>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(line: &str) -> Logger {
+        Logger::from_line(&line.to_string()).unwrap()
+    }
+
+    fn health() -> Logger {
+        access(
+            r#"10.0.0.5 - - [17/Sep/2022:23:39:19 +0200] "example.com" "GET /health HTTP/1.1" 200 2 "-" "kube-probe/1.27""#,
+        )
+    }
+
+    fn page() -> Logger {
+        access(
+            r#"84.213.100.23 - - [17/Sep/2022:23:39:19 +0200] "example.com" "GET /aaa HTTP/1.1" 404 10 "-" "curl/8""#,
+        )
+    }
+
+    #[test]
+    fn commas_inside_a_regex() {
+        assert_eq!(
+            split_conditions("path=~^/a{1,3}$,status=400-499"),
+            vec!["path=~^/a{1,3}$", "status=400-499"]
+        );
+        let mut filter = Filter::default();
+        filter
+            .add(Action::Drop, "path=~^/a{1,3}$,status=400-499")
+            .unwrap();
+        assert!(!filter.allows(&page()));
+        assert!(filter.allows(&health()));
+    }
+
+    #[test]
+    fn drop_and_keep() {
+        let mut filter = Filter::default();
+        filter.add(Action::Keep, "ip=84.213.0.0/16").unwrap();
+        filter
+            .add(Action::Keep, "host=EXAMPLE.com,status=200")
+            .unwrap();
+        filter.add(Action::Drop, "user_agent=~^kube-probe").unwrap();
+        assert!(filter.allows(&page()));
+        assert!(!filter.allows(&health()));
+        assert_eq!(filter.total_dropped(), 1);
+
+        let mut filter = Filter::default();
+        filter.add(Action::Keep, "status=500-599").unwrap();
+        assert!(!filter.allows(&page()));
+        assert_eq!(filter.not_kept(), 1);
+    }
+
+    #[test]
+    fn sampling() {
+        let mut filter = Filter::default();
+        filter.add_sample("3:path=~^/health").unwrap();
+        let allowed = (0..9).filter(|_| filter.allows(&health())).count();
+        assert_eq!(allowed, 3);
+        assert!(filter.allows(&page()));
+        assert_eq!(filter.rules()[0].dropped(), 6);
+        assert!(filter.add_sample("0:status=200").is_err());
+    }
+
+    #[test]
+    fn invalid_conditions() {
+        let mut filter = Filter::default();
+        assert!(filter.add(Action::Drop, "status=299-200").is_err());
+        assert!(filter.add(Action::Drop, "path=/health").is_err());
+        assert!(filter.add(Action::Drop, "referer=x").is_err());
+        assert!(filter.is_empty());
+    }
+}
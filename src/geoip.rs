@@ -0,0 +1,154 @@
+use anyhow::{Context, Error, Result};
+use maxminddb::{geoip2, Reader};
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the database files are checked for updates
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where an ip address is, and who it belongs to
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Geo {
+    pub country_code: Option<String>,
+    pub country: Option<String>,
+    pub city: Option<String>,
+    pub location: Option<GeoPoint>,
+    pub asn: Option<u32>,
+    pub as_org: Option<String>,
+}
+
+/// Serializes the way elasticsearch expects a geo_point
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GeoPoint {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// A .mmdb file, reloaded when it changes on disk
+pub struct GeoDatabase {
+    path: PathBuf,
+    reader: RwLock<Arc<Reader<Vec<u8>>>>,
+    modified: Mutex<SystemTime>,
+    checked: Mutex<Instant>,
+}
+
+impl GeoDatabase {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let modified = fs::metadata(path)
+            .and_then(|m| m.modified())
+            .with_context(|| format!("Unable to read {:?}", path))?;
+        let reader = Reader::open_readfile(path)
+            .with_context(|| format!("Unable to open {:?} as a MaxMind database", path))?;
+        Ok(GeoDatabase {
+            path: path.to_path_buf(),
+            reader: RwLock::new(Arc::new(reader)),
+            modified: Mutex::new(modified),
+            checked: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Like GeoLite2-City or GeoLite2-ASN
+    pub fn database_type(&self) -> String {
+        self.reader.read().unwrap().metadata.database_type.clone()
+    }
+
+    /// The current database, reloading it first if the file was replaced
+    fn reader(&self) -> Arc<Reader<Vec<u8>>> {
+        let due = {
+            let mut checked = self.checked.lock().unwrap();
+            let due = checked.elapsed() >= RELOAD_CHECK_INTERVAL;
+            if due {
+                *checked = Instant::now();
+            }
+            due
+        };
+        if due {
+            self.reload();
+        }
+        self.reader.read().unwrap().clone()
+    }
+
+    fn reload(&self) {
+        let modified = match fs::metadata(&self.path).and_then(|m| m.modified()) {
+            Ok(m) => m,
+            // Might be in the middle of being replaced, try again next time
+            Err(_) => return,
+        };
+        if modified == *self.modified.lock().unwrap() {
+            return;
+        }
+        match Reader::open_readfile(&self.path) {
+            Ok(reader) => {
+                *self.reader.write().unwrap() = Arc::new(reader);
+                *self.modified.lock().unwrap() = modified;
+                println!("Reloaded {:?}", self.path);
+            }
+            Err(e) => eprintln!(
+                "Unable to reload {:?}, keeping the old one: {}",
+                self.path, e
+            ),
+        }
+    }
+}
+
+/// Looks up ip addresses in a City (or Country) and an ASN database
+#[derive(Default)]
+pub struct GeoIp {
+    pub city: Option<GeoDatabase>,
+    pub asn: Option<GeoDatabase>,
+}
+
+impl GeoIp {
+    pub fn is_enabled(&self) -> bool {
+        self.city.is_some() || self.asn.is_some()
+    }
+
+    /// None when neither database knows the address, like private addresses
+    pub fn lookup(&self, ip: IpAddr) -> Option<Geo> {
+        let mut geo = Geo::default();
+        let mut found = false;
+
+        if let Some(db) = &self.city {
+            let reader = db.reader();
+            if let Ok(city) = reader.lookup::<geoip2::City>(ip) {
+                found = true;
+                if let Some(country) = city.country {
+                    geo.country_code = country.iso_code.map(String::from);
+                    geo.country = english(&country.names);
+                }
+                geo.city = city.city.and_then(|c| english(&c.names));
+                geo.location = city.location.and_then(|l| {
+                    Some(GeoPoint {
+                        lat: l.latitude?,
+                        lon: l.longitude?,
+                    })
+                });
+            }
+        }
+
+        if let Some(db) = &self.asn {
+            let reader = db.reader();
+            if let Ok(asn) = reader.lookup::<geoip2::Asn>(ip) {
+                found = true;
+                geo.asn = asn.autonomous_system_number;
+                geo.as_org = asn.autonomous_system_organization.map(String::from);
+            }
+        }
+
+        match found {
+            true => Some(geo),
+            false => None,
+        }
+    }
+}
+
+fn english(names: &Option<std::collections::BTreeMap<&str, &str>>) -> Option<String> {
+    names
+        .as_ref()
+        .and_then(|n| n.get("en"))
+        .map(|n| n.to_string())
+}
@@ -0,0 +1,256 @@
+use chrono::{Months, NaiveDate, TimeZone, Utc};
+use serde_json::{json, Value};
+use std::fmt;
+
+use crate::utils::Prompt;
+
+/// How often a new index is started when rolling indices
+#[derive(Clone, Copy, PartialEq)]
+pub enum Rollover {
+    Daily,
+    Monthly,
+}
+
+impl Rollover {
+    pub fn parse(str: &str) -> Option<Self> {
+        match str {
+            "daily" => Some(Rollover::Daily),
+            "monthly" => Some(Rollover::Monthly),
+            _ => None,
+        }
+    }
+
+    fn format(&self) -> &'static str {
+        match self {
+            Rollover::Daily => "%Y.%m.%d",
+            Rollover::Monthly => "%Y.%m",
+        }
+    }
+
+    /// The index a document belongs in, based on its time in milliseconds
+    /// logger + 1697500000000 → logger-2023.10.16
+    pub fn index_name(&self, base: &str, epoch_millis: u64) -> String {
+        let date = Utc
+            .timestamp_millis(epoch_millis as i64)
+            .format(self.format());
+        format!("{}-{}", base, date)
+    }
+
+    /// The last day covered by an index, None if the name isn't one of ours
+    /// logger-2023.10 → 2023-10-31
+    pub fn last_day(&self, base: &str, name: &str) -> Option<NaiveDate> {
+        let date = name.strip_prefix(base)?.strip_prefix('-')?;
+        match self {
+            Rollover::Daily => NaiveDate::parse_from_str(date, "%Y.%m.%d").ok(),
+            Rollover::Monthly => {
+                let first = NaiveDate::parse_from_str(&format!("{}.01", date), "%Y.%m.%d").ok()?;
+                first.checked_add_months(Months::new(1))?.pred_opt()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Rollover {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rollover::Daily => write!(f, "daily"),
+            Rollover::Monthly => write!(f, "monthly"),
+        }
+    }
+}
+
+/// How the documents are laid out in elasticsearch
+#[derive(Clone, Default)]
+pub struct IndexOptions {
+    /// Time based indices behind an alias named after the index in the url
+    pub rollover: Option<Rollover>,
+    /// Writes to a data stream named after the index in the url
+    pub data_stream: bool,
+    /// Lets an ILM policy handle the retention instead of the archive thread
+    pub ilm: bool,
+    /// How many days the documents are kept, the ILM policy deletes them a little later
+    pub retention_days: u16,
+    /// How the question to create a missing index is answered
+    pub prompt: Prompt,
+}
+
+impl IndexOptions {
+    /// Both rolling indices and data streams get their mapping from an index template
+    pub fn uses_template(&self) -> bool {
+        self.rollover.is_some() || self.data_stream
+    }
+}
+
+/// Extra days before ILM deletes anything, so the archive thread gets to it first
+const ILM_DELETE_MARGIN_DAYS: u16 = 2;
+
+/// Name of the ILM policy belonging to an index
+pub fn policy_name(index: &str) -> String {
+    format!("{}-policy", index)
+}
+
+/// hot → warm → delete.
+/// The hot phase only rolls over when writing through a write alias or data stream,
+/// time based indices are already split up by nginx-es itself
+pub fn ilm_policy(delete_after_days: u16, rollover: bool) -> Value {
+    let hot_actions = if rollover {
+        json!({
+            "rollover": {
+                "max_age": "1d",
+                "max_primary_shard_size": "50gb"
+            },
+            "set_priority": { "priority": 100 }
+        })
+    } else {
+        json!({
+            "set_priority": { "priority": 100 }
+        })
+    };
+    json!({
+        "policy": {
+            "phases": {
+                "hot": {
+                    "min_age": "0ms",
+                    "actions": hot_actions
+                },
+                "warm": {
+                    "min_age": "7d",
+                    "actions": {
+                        "set_priority": { "priority": 50 }
+                    }
+                },
+                "delete": {
+                    "min_age": format!("{}d", delete_after_days + ILM_DELETE_MARGIN_DAYS),
+                    "actions": {
+                        "delete": {}
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(y: i32, m: u32, d: u32, h: u32, min: u32, s: u32) -> u64 {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .and_then(|d| d.and_hms_opt(h, min, s))
+            .unwrap()
+            .timestamp_millis() as u64
+    }
+
+    fn date(y: i32, m: u32, d: u32) -> Option<NaiveDate> {
+        NaiveDate::from_ymd_opt(y, m, d)
+    }
+
+    #[test]
+    fn index_names() {
+        assert_eq!(
+            Rollover::Daily.index_name("logger", 1697500000000),
+            "logger-2023.10.16"
+        );
+        assert_eq!(
+            Rollover::Monthly.index_name("logger", 1697500000000),
+            "logger-2023.10"
+        );
+    }
+
+    #[test]
+    fn day_boundaries() {
+        // The day changes at midnight UTC, whatever the timezone of the server
+        let midnight = millis(2023, 10, 17, 0, 0, 0);
+        assert_eq!(
+            Rollover::Daily.index_name("logger", midnight - 1),
+            "logger-2023.10.16"
+        );
+        assert_eq!(
+            Rollover::Daily.index_name("logger", midnight),
+            "logger-2023.10.17"
+        );
+
+        let new_month = millis(2024, 3, 1, 0, 0, 0);
+        assert_eq!(
+            Rollover::Daily.index_name("logger", new_month - 1),
+            "logger-2024.02.29"
+        );
+        assert_eq!(
+            Rollover::Monthly.index_name("logger", new_month - 1),
+            "logger-2024.02"
+        );
+        assert_eq!(
+            Rollover::Monthly.index_name("logger", new_month),
+            "logger-2024.03"
+        );
+
+        let new_year = millis(2024, 1, 1, 0, 0, 0);
+        assert_eq!(
+            Rollover::Monthly.index_name("logger", new_year - 1),
+            "logger-2023.12"
+        );
+        assert_eq!(
+            Rollover::Daily.index_name("logger", new_year),
+            "logger-2024.01.01"
+        );
+    }
+
+    #[test]
+    fn last_days() {
+        let daily = Rollover::Daily;
+        let monthly = Rollover::Monthly;
+        assert_eq!(
+            daily.last_day("logger", "logger-2023.10.16"),
+            date(2023, 10, 16)
+        );
+        assert_eq!(
+            monthly.last_day("logger", "logger-2023.10"),
+            date(2023, 10, 31)
+        );
+        assert_eq!(
+            monthly.last_day("logger", "logger-2023.12"),
+            date(2023, 12, 31)
+        );
+        assert_eq!(
+            monthly.last_day("logger", "logger-2024.02"),
+            date(2024, 2, 29)
+        );
+        assert_eq!(
+            monthly.last_day("logger", "logger-2023.02"),
+            date(2023, 2, 28)
+        );
+
+        // Every name made for a time is covered up to its last day
+        let time = millis(2023, 10, 31, 23, 59, 59);
+        for rollover in [daily, monthly] {
+            let name = rollover.index_name("logger", time);
+            assert_eq!(rollover.last_day("logger", &name), date(2023, 10, 31));
+        }
+    }
+
+    #[test]
+    fn other_indices_are_left_alone() {
+        let daily = Rollover::Daily;
+        let monthly = Rollover::Monthly;
+        assert_eq!(daily.last_day("logger", "logger_errors"), None);
+        assert_eq!(daily.last_day("logger", "logger-000001"), None);
+        assert_eq!(daily.last_day("logger", "logger2023.10.16"), None);
+        assert_eq!(daily.last_day("logger", "other-2023.10.16"), None);
+        assert_eq!(daily.last_day("logger", "logger-web-2023.10.16"), None);
+        assert_eq!(daily.last_day("logger", "logger-2023.10"), None);
+        assert_eq!(monthly.last_day("logger", "logger-2023.10.16"), None);
+        assert_eq!(monthly.last_day("logger", "logger-2023.13"), None);
+    }
+
+    #[test]
+    fn policies() {
+        let policy = ilm_policy(30, false);
+        assert_eq!(policy["policy"]["phases"]["delete"]["min_age"], "32d");
+        assert!(policy["policy"]["phases"]["hot"]["actions"]["rollover"].is_null());
+        let policy = ilm_policy(30, true);
+        assert_eq!(
+            policy["policy"]["phases"]["hot"]["actions"]["rollover"]["max_age"],
+            "1d"
+        );
+    }
+}
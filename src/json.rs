@@ -0,0 +1,195 @@
+use anyhow::{bail, Context, Error, Result};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// Where the value of a key in a JSON log line ends up
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Field {
+    /// The X-Forwarded-For chain
+    Ip,
+    /// The address connecting to nginx, it goes at the end of the chain
+    RemoteAddr,
+    Time,
+    Host,
+    Request,
+    Method,
+    /// Path and query together, like $request_uri
+    Uri,
+    Path,
+    Query,
+    Protocol,
+    StatusCode,
+    Size,
+    Refer,
+    UserAgent,
+    /// One of the timing fields, by its name
+    Timing(&'static str),
+}
+
+impl Field {
+    pub fn parse(str: &str) -> Option<Self> {
+        Some(match str {
+            "ip" => Field::Ip,
+            "remote_addr" => Field::RemoteAddr,
+            "time" => Field::Time,
+            "host" => Field::Host,
+            "request" => Field::Request,
+            "method" => Field::Method,
+            "uri" => Field::Uri,
+            "path" => Field::Path,
+            "query" => Field::Query,
+            "protocol" => Field::Protocol,
+            "status_code" => Field::StatusCode,
+            "size" => Field::Size,
+            "refer" => Field::Refer,
+            "user_agent" => Field::UserAgent,
+            "request_time" => Field::Timing("request_time"),
+            "upstream_response_time" => Field::Timing("upstream_response_time"),
+            "upstream_status" => Field::Timing("upstream_status"),
+            "upstream_addr" => Field::Timing("upstream_addr"),
+            "bytes_sent" => Field::Timing("bytes_sent"),
+            _ => return None,
+        })
+    }
+}
+
+/// Which key of a JSON log line goes into which field.
+/// When several keys go into the same field, the first one in the table that's present wins
+#[derive(Clone)]
+pub struct JsonFields {
+    table: Vec<(String, Field)>,
+}
+
+impl Default for JsonFields {
+    /// The names of the nginx variables, as in `"remote_addr":"$remote_addr"`
+    fn default() -> Self {
+        let table = [
+            ("http_x_forwarded_for", Field::Ip),
+            ("remote_addr", Field::RemoteAddr),
+            // The most precise time first
+            ("msec", Field::Time),
+            ("time_iso8601", Field::Time),
+            ("time_local", Field::Time),
+            ("host", Field::Host),
+            ("server_name", Field::Host),
+            ("request", Field::Request),
+            ("request_method", Field::Method),
+            ("request_uri", Field::Uri),
+            ("uri", Field::Path),
+            ("args", Field::Query),
+            ("query_string", Field::Query),
+            ("server_protocol", Field::Protocol),
+            ("status", Field::StatusCode),
+            ("body_bytes_sent", Field::Size),
+            ("http_referer", Field::Refer),
+            ("http_user_agent", Field::UserAgent),
+            ("request_time", Field::Timing("request_time")),
+            (
+                "upstream_response_time",
+                Field::Timing("upstream_response_time"),
+            ),
+            ("upstream_status", Field::Timing("upstream_status")),
+            ("upstream_addr", Field::Timing("upstream_addr")),
+            ("bytes_sent", Field::Timing("bytes_sent")),
+        ];
+        JsonFields {
+            table: table
+                .into_iter()
+                .map(|(key, field)| (key.to_string(), field))
+                .collect(),
+        }
+    }
+}
+
+impl JsonFields {
+    /// Adds `key=field`, it takes priority over the defaults
+    pub fn add(&mut self, spec: &str) -> Result<(), Error> {
+        let (key, field) = spec
+            .split_once('=')
+            .with_context(|| format!("Expected key=field, got {}", spec))?;
+        let field = match Field::parse(field) {
+            Some(f) => f,
+            None => bail!("Unknown field {}", field),
+        };
+        self.table.retain(|(k, _)| k != key);
+        self.table.insert(0, (key.to_string(), field));
+        Ok(())
+    }
+
+    /// Picks the values out of a JSON log line, as strings.
+    /// Empty values and nginx's `-` are left out
+    pub fn extract(&self, line: &str) -> Result<HashMap<Field, String>, Error> {
+        let json: Map<String, Value> = serde_json::from_str(line)?;
+        let mut values = HashMap::new();
+        for (key, field) in &self.table {
+            if values.contains_key(field) {
+                continue;
+            }
+            if let Some(value) = json.get(key).and_then(coerce) {
+                values.insert(*field, value);
+            }
+        }
+        Ok(values)
+    }
+}
+
+/// Numbers and strings alike end up as a string, to be parsed the same way as a regular log line
+fn coerce(value: &Value) -> Option<String> {
+    let value = match value {
+        Value::String(s) => s.trim().to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Array(a) => {
+            let values: Vec<String> = a.iter().filter_map(coerce).collect();
+            values.join(", ")
+        }
+        Value::Null | Value::Object(_) => return None,
+    };
+    match value.is_empty() || value == "-" {
+        true => None,
+        false => Some(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn coerced_values() {
+        assert_eq!(coerce(&json!(" a.com ")), Some("a.com".to_string()));
+        assert_eq!(coerce(&json!(0.125)), Some("0.125".to_string()));
+        assert_eq!(coerce(&json!(true)), Some("true".to_string()));
+        assert_eq!(
+            coerce(&json!(["1.2.3.4", "-", 5])),
+            Some("1.2.3.4, 5".to_string())
+        );
+        assert_eq!(coerce(&json!("-")), None);
+        assert_eq!(coerce(&json!("")), None);
+        assert_eq!(coerce(&json!(null)), None);
+        assert_eq!(coerce(&json!({ "a": 1 })), None);
+    }
+
+    #[test]
+    fn first_key_present_wins() {
+        let line = r#"{"time_local":"17/Sep/2022:23:39:19 +0200","msec":"-","status":404,"http_referer":"-","uri":"/a"}"#;
+        let values = JsonFields::default().extract(line).unwrap();
+        assert_eq!(values[&Field::Time], "17/Sep/2022:23:39:19 +0200");
+        assert_eq!(values[&Field::StatusCode], "404");
+        assert!(!values.contains_key(&Field::Refer));
+
+        let mut fields = JsonFields::default();
+        fields.add("real_ip=ip").unwrap();
+        fields.add("uri=query").unwrap();
+        assert!(fields.add("uri=nothing").is_err());
+        assert!(fields.add("uri").is_err());
+        let values = fields
+            .extract(r#"{"real_ip":"1.2.3.4","uri":"/a"}"#)
+            .unwrap();
+        assert_eq!(values[&Field::Ip], "1.2.3.4");
+        assert_eq!(values[&Field::Query], "/a");
+        assert!(!values.contains_key(&Field::Path));
+        assert!(fields.extract("not json").is_err());
+    }
+}
@@ -1,22 +1,35 @@
 use anyhow::{bail, Context, Error, Result};
 use chrono::{DateTime, Utc};
-use colored::Colorize;
 use regex::Regex;
 use reqwest::Response;
 use serde_json;
 use sha1::{Digest, Sha1};
+use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader};
 use std::net::{IpAddr, Ipv4Addr};
 use std::path::Path;
-use std::{fmt, io};
 
 use serde_derive::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 
-use crate::utils::epoch_to_datetime;
+use crate::anonymize::Anonymizer;
+use crate::document::Document;
+use crate::geoip::Geo;
+use crate::index::policy_name;
+use crate::json::{Field, JsonFields};
+use crate::migrate::{diff, Change};
+use crate::proxy::{parse_chain, TrustedProxies};
+use crate::source::Labels;
+use crate::timing::Timing;
+use crate::useragent::Agent;
+use crate::utils::{epoch_millis_to_datetime, Prompt};
 use crate::Server;
 
+/// Format of the date fields, the default of elasticsearch.
+/// nginx-es writes epoch milliseconds, but ISO 8601 is accepted as well
+const DATE_FORMAT: &str = "strict_date_optional_time||epoch_millis";
+
 ///
 /// When will nested structs be supported
 #[derive(Serialize, Deserialize)]
@@ -31,14 +44,40 @@ struct Mappings {
 #[derive(Serialize, Deserialize)]
 struct Properties {
     ip: Ip,
-    alt_ip: Ip,
+    client_ip: Ip,
+    invalid_ips: Keyword,
+    geo: Object<GeoProperties>,
     host: Text,
     request: Text,
+    method: Keyword,
+    path: Text,
+    query: Text,
+    protocol: Keyword,
     refer: Text,
     status_code: Short,
     size: Integer,
     user_agent: Text,
-    time: EpochS,
+    browser: Keyword,
+    browser_version: Keyword,
+    os: Keyword,
+    os_version: Keyword,
+    device: Keyword,
+    is_bot: Boolean,
+    request_time: Float,
+    upstream_response_time: Float,
+    upstream_status: Keyword,
+    upstream_addr: Keyword,
+    bytes_sent: Long,
+    source: Keyword,
+    labels: Flattened,
+    time: Date,
+    /// Only there for data streams, which require it
+    #[serde(
+        rename = "@timestamp",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    timestamp: Option<Date>,
 }
 #[derive(Serialize, Deserialize)]
 struct Ip {
@@ -63,11 +102,45 @@ struct Short {
     r#type: String,
 }
 #[derive(Serialize, Deserialize)]
+struct Object<T> {
+    properties: T,
+}
+#[derive(Serialize, Deserialize)]
+struct GeoProperties {
+    country_code: Keyword,
+    country: Keyword,
+    city: Keyword,
+    location: GeoPointType,
+    asn: Long,
+    as_org: Keyword,
+}
+#[derive(Serialize, Deserialize)]
+struct GeoPointType {
+    r#type: String,
+}
+#[derive(Serialize, Deserialize)]
+struct Float {
+    r#type: String,
+}
+#[derive(Serialize, Deserialize)]
+struct Long {
+    r#type: String,
+}
+#[derive(Serialize, Deserialize)]
+struct Boolean {
+    r#type: String,
+}
+#[derive(Serialize, Deserialize)]
 struct Integer {
     r#type: String,
 }
+/// Any keys, their values are indexed as keywords
 #[derive(Serialize, Deserialize)]
-struct EpochS {
+struct Flattened {
+    r#type: String,
+}
+#[derive(Serialize, Deserialize)]
+struct Date {
     r#type: String,
     format: String,
 }
@@ -80,9 +153,14 @@ impl Mapping {
                     ip: Ip {
                         r#type: "ip".to_string(),
                     },
-                    alt_ip: Ip {
+                    client_ip: Ip {
                         r#type: "ip".to_string(),
                     },
+                    invalid_ips: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 256,
+                    },
+                    geo: GeoProperties::object(),
                     host: Text {
                         r#type: "text".to_string(),
                         fields: TextFields {
@@ -101,6 +179,32 @@ impl Mapping {
                             },
                         },
                     },
+                    method: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 256,
+                    },
+                    path: Text {
+                        r#type: "text".to_string(),
+                        fields: TextFields {
+                            keyword: Keyword {
+                                r#type: "keyword".to_string(),
+                                ignore_above: 256,
+                            },
+                        },
+                    },
+                    query: Text {
+                        r#type: "text".to_string(),
+                        fields: TextFields {
+                            keyword: Keyword {
+                                r#type: "keyword".to_string(),
+                                ignore_above: 256,
+                            },
+                        },
+                    },
+                    protocol: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 256,
+                    },
                     refer: Text {
                         r#type: "text".to_string(),
                         fields: TextFields {
@@ -125,18 +229,99 @@ impl Mapping {
                             },
                         },
                     },
-                    time: EpochS {
+                    browser: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 256,
+                    },
+                    browser_version: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 256,
+                    },
+                    os: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 256,
+                    },
+                    os_version: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 256,
+                    },
+                    device: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 256,
+                    },
+                    is_bot: Boolean {
+                        r#type: "boolean".to_string(),
+                    },
+                    request_time: Float {
+                        r#type: "float".to_string(),
+                    },
+                    upstream_response_time: Float {
+                        r#type: "float".to_string(),
+                    },
+                    upstream_status: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 256,
+                    },
+                    upstream_addr: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 256,
+                    },
+                    bytes_sent: Long {
+                        r#type: "long".to_string(),
+                    },
+                    source: Keyword {
+                        r#type: "keyword".to_string(),
+                        ignore_above: 256,
+                    },
+                    labels: Flattened {
+                        r#type: "flattened".to_string(),
+                    },
+                    time: Date {
                         r#type: "date".to_string(),
-                        format: "epoch_second".to_string(),
+                        format: DATE_FORMAT.to_string(),
                     },
+                    timestamp: None,
+                },
+            },
+        }
+    }
+
+    /// Data streams need a `@timestamp` field, it's a copy of `time`
+    pub fn for_data_stream() -> Self {
+        let mut mapping = Mapping::new();
+        mapping.mappings.properties.timestamp = Some(Date {
+            r#type: "date".to_string(),
+            format: DATE_FORMAT.to_string(),
+        });
+        mapping
+    }
+}
+
+impl GeoProperties {
+    fn object() -> Object<Self> {
+        let keyword = || Keyword {
+            r#type: "keyword".to_string(),
+            ignore_above: 256,
+        };
+        Object {
+            properties: GeoProperties {
+                country_code: keyword(),
+                country: keyword(),
+                city: keyword(),
+                location: GeoPointType {
+                    r#type: "geo_point".to_string(),
                 },
+                asn: Long {
+                    r#type: "long".to_string(),
+                },
+                as_org: keyword(),
             },
         }
     }
 }
 
 /// Checks if Nginx log has valid format
-pub fn valid_log(loc: &str) -> bool {
+pub fn valid_log(loc: &str, json_fields: &JsonFields, prompt: Prompt) -> bool {
     if Path::new(loc).exists() == false {
         return false;
     }
@@ -161,7 +346,7 @@ pub fn valid_log(loc: &str) -> bool {
     let mut fails = 0;
     for line in reader.lines() {
         if let Ok(l) = line {
-            let result = Logger::from_line(&l);
+            let result = Document::parse(&l, json_fields);
             if counter > 10 {
                 break;
             }
@@ -193,18 +378,12 @@ pub fn valid_log(loc: &str) -> bool {
 
     if error {
         println!("  Do you still wish to continue without fully verifying ?");
-        print!("({}/{}/{}) > ", "y".green(), "n".red(), "q".yellow());
-        let _ = io::stdout().flush();
-        let mut user_input = String::new();
-        let stdin = io::stdin();
-        stdin.read_line(&mut user_input).expect("Expect input");
-        user_input = String::from(user_input.trim());
-        if user_input != "y" && user_input != "q" {
-            // if n or something else
-            return false;
-        } else if user_input == "q" {
-            println!("Quitting...");
-            std::process::exit(0);
+        match prompt.confirm() {
+            Ok(answer) => return answer,
+            Err(e) => {
+                println!("  {}", e);
+                return false;
+            }
         }
     }
 
@@ -214,19 +393,130 @@ pub fn valid_log(loc: &str) -> bool {
 /// Server, containing protocol, hostname, port and db
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Logger {
-    ip: IpAddr,
-    alt_ip: Option<IpAddr>,
+    /// Every address in the X-Forwarded-For chain, the client is one of them
+    ip: Vec<IpAddr>,
+    client_ip: IpAddr,
+    /// Entries in the chain that aren't an ip address
+    invalid_ips: Vec<String>,
+    /// Only filled in when GeoIP databases are given
+    geo: Option<Geo>,
     host: Option<String>,
     request: String,
+    method: Option<String>,
+    path: Option<String>,
+    query: Option<String>,
+    protocol: Option<String>,
     refer: Option<String>,
     status_code: u16,
     size: u64,
     user_agent: Option<String>,
-    time: u64, // Who knows if this program lives to be 83 years old
+    /// Only filled in when parsing user agents
+    #[serde(flatten)]
+    agent: Option<Agent>,
+    #[serde(flatten)]
+    timing: Timing,
+    /// The file, pipe or syslog sender the line came from
+    source: Option<String>,
+    /// Static fields given for the source, or for all of them
+    labels: Labels,
+    time: u64, // In milliseconds. Who knows if this program lives to be 584 million years old
+    /// Not part of the document, it's the _id
+    #[serde(skip)]
+    id: Option<String>,
+    /// Another index than the one in the url, when the source picks one
+    #[serde(skip)]
+    index: Option<String>,
 }
 impl Logger {
+    /// Parses either a JSON log line or a regular one
+    pub fn parse(line: &String, json_fields: &JsonFields) -> Result<Self, Error> {
+        match line.trim_start().starts_with('{') {
+            true => Logger::from_json(line, json_fields),
+            false => Logger::from_line(line),
+        }
+    }
+
+    /// Builds the document from a line of `log_format ... escape=json`,
+    /// the keys are looked up in the json_fields table
+    pub fn from_json(line: &str, json_fields: &JsonFields) -> Result<Self, Error> {
+        let values = json_fields.extract(line)?;
+        let get = |field: Field| values.get(&field).map(String::as_str);
+
+        // The connecting address comes after the X-Forwarded-For chain
+        let chain: Vec<&str> = [get(Field::Ip), get(Field::RemoteAddr)]
+            .into_iter()
+            .flatten()
+            .collect();
+        let (ip, invalid_ips) = parse_chain(&chain.join(", "));
+        let client_ip = TrustedProxies::default()
+            .client_ip(&ip)
+            .context("No ip address in the line")?;
+
+        let time = parse_time(get(Field::Time).context("No time in the line")?)?;
+        let status_code = get(Field::StatusCode)
+            .context("No status in the line")?
+            .parse::<u16>()?;
+        let size = get(Field::Size).and_then(|s| s.parse().ok()).unwrap_or(0);
+
+        // Without $request, it's put together from its parts
+        let uri = get(Field::Uri).or(get(Field::Path)).unwrap_or_default();
+        let request = match get(Field::Request) {
+            Some(r) => r.to_string(),
+            None => [get(Field::Method), Some(uri), get(Field::Protocol)]
+                .into_iter()
+                .flatten()
+                .filter(|p| !p.is_empty())
+                .collect::<Vec<&str>>()
+                .join(" "),
+        };
+        let (mut method, mut path, mut query, mut protocol) = split_request(&request);
+        if let Some(uri) = get(Field::Uri) {
+            match uri.split_once('?') {
+                Some((p, q)) => (path, query) = (Some(p.to_string()), Some(q.to_string())),
+                None => (path, query) = (Some(uri.to_string()), None),
+            }
+        }
+        method = get(Field::Method).map(String::from).or(method);
+        path = get(Field::Path).map(String::from).or(path);
+        query = get(Field::Query).map(String::from).or(query);
+        protocol = get(Field::Protocol).map(String::from).or(protocol);
+
+        let mut timing = Timing::default();
+        for (field, value) in &values {
+            if let Field::Timing(key) = field {
+                timing.set(key, value);
+            }
+        }
+
+        Ok(Logger {
+            ip,
+            client_ip,
+            invalid_ips,
+            host: get(Field::Host).map(String::from),
+            geo: None,
+            request,
+            method,
+            path,
+            query,
+            protocol,
+            refer: get(Field::Refer).map(String::from),
+            status_code,
+            size,
+            user_agent: get(Field::UserAgent).map(String::from),
+            agent: None,
+            timing,
+            source: None,
+            labels: Labels::default(),
+            time,
+            id: None,
+            index: None,
+        })
+    }
+
     pub fn from_line(line: &String) -> Result<Self, Error> {
-        let re = Regex::new(r#"(.*) .* .* \[(.*)\] "(.*)" "(.*)" (\d+) (\d+) "(.*)" "(.*)""#)?;
+        let re = Regex::new(
+            r#"^(.*?) \S+ \S+ \[([^\]]*)\] "([^"]*)" "([^"]*)" (\d+) (\d+) "([^"]*)" "([^"]*)""#,
+        )?;
         //if re.is_match(line.as_str()) == false {
         //    bail!("Regex did not match line");
         //}
@@ -237,25 +527,14 @@ impl Logger {
             .captures(line)
             .context("Regex did not get any captures")?;
 
-        // Getting ip(s)
-        let mut ip_arg = &cap[1];
-        let mut alt_ip_arg: Option<String> = None;
-        if ip_arg.contains(",") {
-            let split: Vec<&str> = ip_arg.split(",").collect();
-            ip_arg = split[0].trim();
-            alt_ip_arg = Some(String::from(split[1].trim()));
-        }
-
-        // verify ip addresses
-        let ip: IpAddr = ip_arg.parse::<IpAddr>()?;
-        let alt_ip: Option<IpAddr> = alt_ip_arg.and_then(|ip| ip.parse::<IpAddr>().ok());
+        // Getting ip(s), the client is picked again later on with the configured proxies
+        let (ip, invalid_ips) = parse_chain(&cap[1]);
+        let client_ip = TrustedProxies::default()
+            .client_ip(&ip)
+            .with_context(|| format!("No ip address in {}", &cap[1]))?;
 
         // Getting the date
-        // The format from nginx looks like this: 17/Sep/2022:23:39:19 +0200
-        // It will fail if it isn't this format
-        let time = DateTime::parse_from_str(&cap[2], "%d/%b/%Y:%H:%M:%S %z")?
-            .with_timezone(&Utc)
-            .timestamp() as u64;
+        let time = parse_time(&cap[2])?;
 
         // Getting the domain
         let host = if &cap[3] != "-" {
@@ -265,6 +544,7 @@ impl Logger {
         };
 
         let request = cap[4].to_string();
+        let (method, path, query, protocol) = split_request(&request);
         let status_code = (&cap[5]).parse::<u16>()?;
         let size = (&cap[6]).parse::<u64>()?;
         let refer = if &cap[7] != "-" {
@@ -278,36 +558,85 @@ impl Logger {
             None
         };
 
+        // Optional key=value pairs after the user agent
+        let timing = Timing::parse(&line[cap.get(0).unwrap().end()..]);
+
         Ok(Logger {
             ip,
+            client_ip,
+            invalid_ips,
             host,
-            alt_ip,
+            geo: None,
             request,
+            method,
+            path,
+            query,
+            protocol,
             refer,
             status_code,
             size,
             user_agent,
+            agent: None,
+            timing,
+            source: None,
+            labels: Labels::default(),
             time,
+            id: None,
+            index: None,
         })
     }
 
     pub fn from_es(es: &Value) -> Option<Self> {
         // These values are required
-        let ip: IpAddr = es.get("ip")?.as_str()?.parse().ok()?;
+        let mut ip: Vec<IpAddr> = match es.get("ip")? {
+            Value::Array(a) => a.iter().filter_map(|i| i.as_str()?.parse().ok()).collect(),
+            i => vec![i.as_str()?.parse().ok()?],
+        };
         let request = es.get("request")?.as_str()?.to_string();
         let status_code = es.get("status_code")?.as_u64()? as u16;
-        let time = es.get("time")?.as_u64()?;
+        let time = time_from_es(es.get("time")?)?;
         let size = es.get("size")?.as_u64()?;
 
-        // Option field for alt_ip
-        let alt_ip: Option<IpAddr> = es
+        // Documents from before the whole chain was kept have the second address in alt_ip,
+        // and the first one was taken as the client
+        if let Some(alt_ip) = es
             .get("alt_ip")
             .and_then(|ai_j| ai_j.as_str())
-            .and_then(|ai_str| ai_str.parse().ok());
+            .and_then(|ai_str| ai_str.parse().ok())
+        {
+            ip.push(alt_ip);
+        }
+        let client_ip: IpAddr = es
+            .get("client_ip")
+            .and_then(|c| c.as_str())
+            .and_then(|c| c.parse().ok())
+            .or(ip.first().copied())?;
+        let invalid_ips = es
+            .get("invalid_ips")
+            .and_then(|i| i.as_array())
+            .map(|a| {
+                a.iter()
+                    .filter_map(|i| i.as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default();
 
         // Option field for host
         let host = es.get("host").and_then(|s| Some(s.to_string()));
 
+        // Documents from before the request was split up only have the raw line
+        let (method, path, query, protocol) = match es.get("method") {
+            Some(_) => (
+                es.get("method").and_then(|s| s.as_str()).map(String::from),
+                es.get("path").and_then(|s| s.as_str()).map(String::from),
+                es.get("query").and_then(|s| s.as_str()).map(String::from),
+                es.get("protocol")
+                    .and_then(|s| s.as_str())
+                    .map(String::from),
+            ),
+            None => split_request(&request),
+        };
+
         // Option field for refer
         let refer = es.get("refer").and_then(|s| Some(s.to_string()));
 
@@ -316,14 +645,29 @@ impl Logger {
 
         Some(Logger {
             ip,
-            alt_ip,
+            client_ip,
+            invalid_ips,
+            geo: None,
             host,
             request,
+            method,
+            path,
+            query,
+            protocol,
             refer,
             status_code,
             size,
             user_agent,
+            agent: None,
+            timing: Timing::from_es(es),
+            source: es.get("source").and_then(|s| s.as_str()).map(String::from),
+            labels: es
+                .get("labels")
+                .and_then(|l| serde_json::from_value(l.clone()).ok())
+                .unwrap_or_default(),
             time,
+            id: None,
+            index: None,
         })
     }
 
@@ -331,15 +675,27 @@ impl Logger {
     /// use the new() function for actual new logging
     pub fn dummy_data() -> Self {
         Logger {
-            ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-            alt_ip: None,
+            ip: vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))],
+            client_ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            invalid_ips: vec![],
+            geo: None,
             host: None,
             request: String::new(),
+            method: None,
+            path: None,
+            query: None,
+            protocol: None,
             refer: None,
             status_code: 200,
             size: 420,
             user_agent: None,
+            agent: Some(Agent::default()),
+            timing: Timing::default(),
+            source: None,
+            labels: Labels::default(),
             time: 0,
+            id: None,
+            index: None,
         }
     }
 
@@ -376,38 +732,199 @@ impl Logger {
         Logger::double_check_mapping()?;
 
         let j: Value = res.json().await?;
+        // When the index is an alias (ILM), the newest index behind it is checked
         let keys = j
             .get(db.clone())
+            .or_else(|| j.as_object().and_then(|o| o.values().next_back()))
             .and_then(|db_json| db_json.get("mappings"))
             .and_then(|mappings_json| mappings_json.get("properties"))
             .and_then(|prop_json| prop_json.as_object())
             .with_context(|| format!("Unable to find {}.mappings.properties", db))?;
-        let mapping: Mapping = Mapping::new();
+        Logger::compare_properties(keys, Mapping::new())
+    }
+
+    /// Same as valid_mapping, but for the index template used by rolling indices and data streams
+    pub async fn valid_template_mapping(server: &Server, res: Response) -> Result<(), Error> {
+        Logger::double_check_mapping()?;
+        let name = server.index();
+
+        let j: Value = res.json().await?;
+        let keys = j
+            .get("index_templates")
+            .and_then(|t| t.as_array())
+            .and_then(|t| t.iter().find(|t| t["name"] == name))
+            .and_then(|t| t["index_template"]["template"]["mappings"].get("properties"))
+            .and_then(|prop_json| prop_json.as_object())
+            .with_context(|| format!("Unable to find the mappings of template {}", name))?;
+        let mapping = match server.index_options().data_stream {
+            true => Mapping::for_data_stream(),
+            false => Mapping::new(),
+        };
+        Logger::compare_properties(keys, mapping)
+    }
+
+    fn compare_properties(
+        keys: &serde_json::Map<String, Value>,
+        mapping: Mapping,
+    ) -> Result<(), Error> {
         let keys2 = serde_json::to_value(mapping.mappings.properties)
             .unwrap()
             .as_object()
             .unwrap()
             .clone();
 
-        for elm in keys.keys() {
-            if keys2.contains_key(elm) == false {
-                bail!("Should not contain: {}", elm);
+        if let Some(change) = diff(keys, &keys2).into_iter().next() {
+            match change {
+                Change::Removed(field) => {
+                    bail!("Should not contain: {} (run `nginx-es migrate`)", field)
+                }
+                Change::Added(field, _) => {
+                    bail!("DB does not contain: {} (run `nginx-es migrate`)", field)
+                }
+                Change::Changed(field, from, to) => bail!(
+                    "{} is {} but should be {} (run `nginx-es migrate`)",
+                    field,
+                    from,
+                    to
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// The properties nginx-es expects in the mapping
+    pub fn expected_properties(data_stream: bool) -> serde_json::Map<String, Value> {
+        let mapping = match data_stream {
+            true => Mapping::for_data_stream(),
+            false => Mapping::new(),
+        };
+        serde_json::to_value(mapping.mappings.properties)
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .clone()
+    }
+
+    /// Mappings and settings for a new regular index
+    pub fn new_index_body(server: &Server) -> Value {
+        let mut body = json!({ "mappings": Mapping::new().mappings });
+        if server.index_options().ilm {
+            body["settings"] = json!({
+                "index.lifecycle.name": policy_name(server.index()),
+                "index.lifecycle.rollover_alias": server.index()
+            });
+        }
+        body
+    }
+
+    /// Creates the index template for rolling indices or a data stream.
+    /// For data streams, the data stream itself is created right after
+    pub async fn create_template(server: &Server) -> Result<(), Error> {
+        Logger::put_template(server).await?;
+
+        if server.index_options().data_stream {
+            let request = server
+                .http()
+                .put(format!(
+                    "{}/_data_stream/{}",
+                    server.get_host(),
+                    server.index()
+                ))
+                .send()
+                .await?
+                .text()
+                .await?;
+            let res: Value = serde_json::from_str(request.as_str())?;
+            if res["acknowledged"].as_bool() != Some(true) {
+                bail!(request);
             }
         }
-        for elm in keys2.keys() {
-            if keys.contains_key(elm) == false {
-                bail!("DB does not contain: {}", elm);
+
+        Ok(())
+    }
+
+    /// Creates or updates the index template.
+    /// For rolling indices, every index matching `<index>-*` gets the mapping and the alias named after the index.
+    /// With ILM on a regular index, it's what the indices the policy rolls over to get their mapping from
+    pub async fn put_template(server: &Server) -> Result<(), Error> {
+        Logger::double_check_mapping()?;
+        let data_stream = server.index_options().data_stream;
+        let mut template = if data_stream {
+            json!({
+                "index_patterns": [server.index()],
+                "data_stream": {},
+                "priority": 200,
+                "template": {
+                    "mappings": Mapping::for_data_stream().mappings
+                }
+            })
+        } else {
+            json!({
+                "index_patterns": [format!("{}-*", server.index())],
+                "priority": 200,
+                "template": {
+                    "mappings": Mapping::new().mappings
+                }
+            })
+        };
+        // With ILM on a regular index, the write alias is only on the newest index.
+        // The ones the policy rolls over to need to know the alias to roll over again
+        let write_alias = !data_stream && server.index_options().rollover.is_none();
+        if !data_stream && !write_alias {
+            template["template"]["aliases"] = json!({
+                server.index(): {}
+            });
+        }
+        if server.index_options().ilm {
+            template["template"]["settings"] = json!({
+                "index.lifecycle.name": policy_name(server.index())
+            });
+            if write_alias {
+                template["template"]["settings"]["index.lifecycle.rollover_alias"] =
+                    json!(server.index());
             }
         }
+        let request = server
+            .http()
+            .put(format!(
+                "{}/_index_template/{}",
+                server.get_host(),
+                server.index()
+            ))
+            .json(&template)
+            .send()
+            .await?
+            .text()
+            .await?;
+
+        let res: Value = serde_json::from_str(request.as_str())?;
+        if res["acknowledged"].as_bool() != Some(true) {
+            bail!(request);
+        }
+
         Ok(())
     }
 
     pub async fn create_mapping(server: Server) -> Result<(), Error> {
         Logger::double_check_mapping()?;
-        let mapping: Mapping = Mapping::new();
-        let request = reqwest::Client::new()
-            .put(server.get_url())
-            .json(&mapping)
+        // With ILM the index is the write alias of <index>-000001, so the policy can roll it over
+        let (url, body) = if server.index_options().ilm {
+            Logger::put_template(&server).await?;
+            let mut body = Logger::new_index_body(&server);
+            body["aliases"] = json!({
+                server.index(): { "is_write_index": true }
+            });
+            (
+                format!("{}/{}-000001", server.get_host(), server.index()),
+                body,
+            )
+        } else {
+            (server.get_url(), Logger::new_index_body(&server))
+        };
+        let request = server
+            .http()
+            .put(url)
+            .json(&body)
             .send()
             .await?
             .text()
@@ -423,25 +940,228 @@ impl Logger {
         Ok(())
     }
 
+    /// Epoch in milliseconds
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    pub fn client_ip(&self) -> IpAddr {
+        self.client_ip
+    }
+
+    pub fn status_code(&self) -> u16 {
+        self.status_code
+    }
+
+    /// The path of the request, or the whole request line when it couldn't be split
+    pub fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or(&self.request)
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    /// Picks the client out of the chain again, skipping the given proxies
+    pub fn resolve_client_ip(&mut self, proxies: &TrustedProxies) {
+        if let Some(ip) = proxies.client_ip(&self.ip) {
+            self.client_ip = ip;
+        }
+    }
+
+    pub fn set_geo(&mut self, geo: Option<Geo>) {
+        self.geo = geo;
+    }
+
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref()
+    }
+
+    pub fn set_agent(&mut self, agent: Agent) {
+        self.agent = Some(agent);
+    }
+
+    /// Truncates or pseudonymises the addresses, and redacts the query parameters.
+    /// A time-ip id is made again, the real address could be found back from it
+    pub fn anonymize(&mut self, anonymizer: &Anonymizer) {
+        let time_ip_id = self.id.as_ref().is_some_and(|id| *id == self.get_id());
+        for ip in self.ip.iter_mut() {
+            *ip = anonymizer.ip(*ip);
+        }
+        self.client_ip = anonymizer.ip(self.client_ip);
+        if anonymizer.hides_ips() {
+            // Garbage in the header can still be a real address with a typo
+            self.invalid_ips.clear();
+        }
+        self.request = anonymizer.redact(&self.request);
+        self.query = self.query.as_deref().map(|q| anonymizer.redact(q));
+        self.refer = self.refer.as_deref().map(|r| anonymizer.redact(r));
+        if time_ip_id {
+            self.id = Some(self.get_id());
+        }
+    }
+
+    /// The id of the document, None lets elasticsearch generate one
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Sets the id of the document from the line it was parsed from,
+    /// its source file and the offset of the line in it
+    pub fn assign_id(&mut self, strategy: IdStrategy, line: &str, source: &Path, offset: u64) {
+        self.id = match strategy {
+            IdStrategy::Line => Some(line_id(line, source, offset)),
+            IdStrategy::Auto => None,
+            IdStrategy::TimeIp => Some(self.get_id()),
+        };
+    }
+
+    pub fn index(&self) -> Option<&str> {
+        self.index.as_deref()
+    }
+
+    pub fn set_source(&mut self, source: String, labels: Labels) {
+        self.source = Some(source);
+        self.labels = labels;
+    }
+
+    pub fn set_index(&mut self, index: Option<String>) {
+        self.index = index;
+    }
+
     /// This function will generate the id for the document
-    /// It's sha1(epoch + ip)
+    /// It's sha1(epoch seconds + ip), the same as before the time was in milliseconds
     pub fn get_id(&self) -> String {
         let mut hasher = Sha1::new();
-        let raw = format!("{}{}", self.time, self.ip);
+        let raw = format!("{}{}", self.time / 1000, self.client_ip);
         hasher.update(raw.into_bytes());
         format!("{:X}", hasher.finalize())
     }
 }
 
+/// sha1 of the raw line, its source file and the offset of the line in it
+pub fn line_id(line: &str, source: &Path, offset: u64) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(format!("{}\n{}\n{}", source.display(), offset, line).into_bytes());
+    format!("{:X}", hasher.finalize())
+}
+
+/// How the id of a document is made
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum IdStrategy {
+    /// sha1 of the raw line, its source file and offset. Reading the same line twice gives the same id
+    #[default]
+    Line,
+    /// Generated by elasticsearch, every document is new
+    Auto,
+    /// sha1 of the time and client ip, requests from the same ip in the same second overwrite each other
+    TimeIp,
+}
+
+impl IdStrategy {
+    pub fn parse(str: &str) -> Option<Self> {
+        match str {
+            "line" => Some(IdStrategy::Line),
+            "auto" => Some(IdStrategy::Auto),
+            "time-ip" => Some(IdStrategy::TimeIp),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for IdStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdStrategy::Line => write!(f, "line"),
+            IdStrategy::Auto => write!(f, "auto"),
+            IdStrategy::TimeIp => write!(f, "time-ip"),
+        }
+    }
+}
+
+/// Parses the time of the log line into epoch milliseconds.
+/// Any of these work:
+/// $time_local: 17/Sep/2022:23:39:19 +0200
+/// $time_iso8601: 2022-09-17T23:39:19+02:00
+/// $msec: 1663450759.123
+fn parse_time(str: &str) -> Result<u64, Error> {
+    if let Some((secs, fraction)) = str.split_once('.') {
+        if !fraction.is_empty() && fraction.bytes().all(|b| b.is_ascii_digit()) {
+            // .1 is 100 ms, anything past milliseconds is cut off
+            let millis = format!("{:0<3}", &fraction[..fraction.len().min(3)]);
+            if let (Ok(secs), Ok(millis)) = (secs.parse::<u64>(), millis.parse::<u64>()) {
+                return Ok(secs * 1000 + millis);
+            }
+        }
+    }
+    let time = match str.contains('T') {
+        true => DateTime::parse_from_rfc3339(str)?,
+        false => DateTime::parse_from_str(str, "%d/%b/%Y:%H:%M:%S %z")?,
+    };
+    Ok(time.with_timezone(&Utc).timestamp_millis() as u64)
+}
+
+/// Anything below this is in seconds, it's the year 5138 in seconds and 1973 in milliseconds
+pub const SECONDS_BELOW: u64 = 100_000_000_000;
+
+/// Reads the time of a document in epoch milliseconds.
+/// Documents from before milliseconds were used have epoch seconds, some might have ISO 8601
+pub fn time_from_es(time: &Value) -> Option<u64> {
+    match time {
+        Value::Number(n) => {
+            let n = n.as_u64()?;
+            match n < SECONDS_BELOW {
+                true => Some(n * 1000),
+                false => Some(n),
+            }
+        }
+        Value::String(s) => Some(
+            DateTime::parse_from_rfc3339(s)
+                .ok()?
+                .with_timezone(&Utc)
+                .timestamp_millis() as u64,
+        ),
+        _ => None,
+    }
+}
+
+/// Method, path, query and protocol
+type RequestLine = (
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+/// Splits the request line into method, path, query and protocol
+/// "GET /index.html?page=2 HTTP/1.1" → GET, /index.html, page=2, HTTP/1.1
+/// Anything that doesn't look like a request line (scanners, TLS on the http port) is left unsplit
+fn split_request(request: &str) -> RequestLine {
+    let parts: Vec<&str> = request.split(' ').collect();
+    let (method, target, protocol) = match parts[..] {
+        [method, target, protocol] if protocol.starts_with("HTTP/") => {
+            (method, target, Some(protocol.to_string()))
+        }
+        // HTTP/0.9 has no protocol
+        [method, target] => (method, target, None),
+        _ => return (None, None, None, None),
+    };
+    if method.is_empty() || !method.chars().all(|c| c.is_ascii_uppercase()) {
+        return (None, None, None, None);
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None),
+    };
+    (Some(method.to_string()), Some(path), query, protocol)
+}
+
 impl fmt::Display for Logger {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let ip = &self.ip;
+        let ip = &self.client_ip;
 
-        let alt_ip = if let Some(ai) = self.alt_ip {
-            ai.to_string()
-        } else {
-            "None".to_string()
-        };
+        let chain: Vec<String> = self.ip.iter().map(|i| i.to_string()).collect();
+        let chain = chain.join(",");
 
         let host = if let Some(h) = &self.host {
             h.to_string()
@@ -462,12 +1182,113 @@ impl fmt::Display for Logger {
         } else {
             "None".to_string()
         };
-        let time = epoch_to_datetime(self.time as i64);
+        let time = epoch_millis_to_datetime(self.time as i64);
 
         let line = format!(
             "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
-            time, ip, alt_ip, host, status_code, request, refer, user_agent, size
+            time, ip, chain, host, status_code, request, refer, user_agent, size
         );
         write!(f, "{}", line)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anonymize::IpMode;
+
+    #[test]
+    fn mapping_checks() {
+        let expected = Logger::expected_properties(false);
+        assert!(Logger::compare_properties(&expected, Mapping::new()).is_ok());
+        // Only the data stream mapping has @timestamp
+        let error = Logger::compare_properties(&expected, Mapping::for_data_stream()).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("DB does not contain: @timestamp"));
+
+        let mut extra = expected.clone();
+        extra.insert("alt_ip".to_string(), json!({ "type": "ip" }));
+        let error = Logger::compare_properties(&extra, Mapping::new()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Should not contain: alt_ip (run `nginx-es migrate`)"
+        );
+
+        let mut changed = expected.clone();
+        changed.insert("client_ip".to_string(), json!({ "type": "keyword" }));
+        let error = Logger::compare_properties(&changed, Mapping::new()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            r#"client_ip is {"type":"keyword"} but should be {"type":"ip"} (run `nginx-es migrate`)"#
+        );
+    }
+
+    #[test]
+    fn request_lines() {
+        assert_eq!(
+            split_request("GET /index.html?page=2 HTTP/1.1"),
+            (
+                Some("GET".to_string()),
+                Some("/index.html".to_string()),
+                Some("page=2".to_string()),
+                Some("HTTP/1.1".to_string())
+            )
+        );
+        assert_eq!(
+            split_request("GET /"),
+            (Some("GET".to_string()), Some("/".to_string()), None, None)
+        );
+        // Garbage from scanners, TLS on the plain port and the like
+        assert_eq!(split_request("\\x16\\x03\\x01"), (None, None, None, None));
+        assert_eq!(split_request("get / HTTP/1.1"), (None, None, None, None));
+        assert_eq!(split_request("GET / a b"), (None, None, None, None));
+        assert_eq!(split_request(""), (None, None, None, None));
+    }
+
+    #[test]
+    fn msec_fractions_are_milliseconds() {
+        assert_eq!(parse_time("1663450759.1").unwrap(), 1663450759100);
+        assert_eq!(parse_time("1663450759.10").unwrap(), 1663450759100);
+        assert_eq!(parse_time("1663450759.100").unwrap(), 1663450759100);
+        assert_eq!(parse_time("1663450759.1234").unwrap(), 1663450759123);
+        assert_eq!(parse_time("1663450759.05").unwrap(), 1663450759050);
+    }
+
+    #[test]
+    fn local_and_iso_times() {
+        let millis = 1663450759000;
+        assert_eq!(parse_time("17/Sep/2022:23:39:19 +0200").unwrap(), millis);
+        assert_eq!(parse_time("2022-09-17T23:39:19+02:00").unwrap(), millis);
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn quoted_pairs_stay_out_of_the_user_agent() {
+        let line = r#"127.0.0.1 - - [17/Sep/2022:23:39:19 +0200] "example.com" "GET /a HTTP/1.1" 200 10 "-" "curl/8" request_time=0.5 c="x y""#;
+        let logger = Logger::from_line(&line.to_string()).unwrap();
+        assert_eq!(logger.user_agent(), Some("curl/8"));
+        assert_eq!(logger.timing.request_time, Some(0.5));
+    }
+
+    #[test]
+    fn anonymized_ips() {
+        let line = r#"unknown, 84.213.100.23 - - [17/Sep/2022:23:39:19 +0200] "example.com" "GET /a HTTP/1.1" 200 10 "-" "curl/8""#;
+        let mut logger = Logger::from_line(&line.to_string()).unwrap();
+        assert_eq!(logger.invalid_ips, vec!["unknown"]);
+        let mut anonymizer = Anonymizer::default();
+        anonymizer.ips = IpMode::Truncate;
+        logger.anonymize(&anonymizer);
+        assert_eq!(logger.client_ip, "84.213.100.0".parse::<IpAddr>().unwrap());
+        assert!(logger.invalid_ips.is_empty());
+    }
+
+    #[test]
+    fn time_ip_id_uses_seconds() {
+        let mut logger = Logger::dummy_data();
+        logger.time = 1663450759123;
+        let mut hasher = Sha1::new();
+        hasher.update(format!("1663450759{}", logger.client_ip).into_bytes());
+        assert_eq!(logger.get_id(), format!("{:X}", hasher.finalize()));
+    }
+}
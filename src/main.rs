@@ -1,21 +1,46 @@
+use anyhow::{Error, Result};
 use chrono::{Local, NaiveTime};
-use logwatcher::{LogWatcher, LogWatcherAction};
-use std::{
-    env,
-    sync::{Arc, Mutex},
-    thread,
-    time::Duration,
-};
+use colored::Colorize;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{env, fs, sync::Arc, time::Duration};
+use tokio::io::AsyncBufReadExt;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
 
 // headers
+mod anonymize;
 mod cert;
 mod config;
+mod document;
+mod enrich;
+mod errorlog;
+mod filter;
+mod geoip;
+mod index;
+mod json;
 mod logger;
+mod migrate;
+mod output;
+mod pipe;
+mod pipeline;
+mod proxy;
 pub mod server;
+mod source;
+mod syslog;
+mod tail;
+mod timing;
+mod useragent;
 mod utils;
 
-use crate::logger::Logger;
-use config::Config;
+use crate::document::Format;
+use crate::pipe::Pipe;
+use crate::pipeline::{Line, Pipeline, Stats};
+use crate::syslog::SyslogMessage;
+use crate::tail::{glob_files, matches_glob, Tailer, GLOB_INTERVAL};
+use config::{Command, Config};
 use server::Server;
 
 fn epoch_days_ago(days: i64) -> i64 {
@@ -29,100 +54,320 @@ fn epoch_days_ago(days: i64) -> i64 {
 }
 
 /// archive time in days
-const ARCHIVE_AFTER_DAYS: u16 = 30;
+pub const ARCHIVE_AFTER_DAYS: u16 = 30;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let config = Arc::new(Config::new(args));
+/// How often the filter counters are printed
+const FILTER_REPORT_INTERVAL: Duration = Duration::from_secs(300);
 
-    let log_watchers: Vec<LogWatcher> = {
-        config
-            .nginx_sources
-            .iter()
-            .filter_map(|s| LogWatcher::register(s).ok())
-            .collect()
-    };
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().collect();
+    let config = Arc::new(Config::new(args).await);
 
-    let log_arc: Arc<Mutex<Vec<Logger>>> = Arc::new(Mutex::new(vec![]));
+    if let Command::Migrate { dry_run } = config.command {
+        for server in config.outputs.iter().filter_map(|o| o.server()) {
+            if let Err(e) = migrate::migrate(server, dry_run).await {
+                eprintln!("{}", format!("Migration of {} failed: {}", server, e).red());
+                std::process::exit(1);
+            }
+            println!();
+        }
+        return;
+    }
 
+    // Everything runs as a task on this one runtime, the sources, the archiving and the reports
     let mut handles = vec![];
 
-    // Archive thread
+    // Archive task
     if config.archive_folder.is_some() {
         let config = config.clone();
-        let handle = thread::spawn(move || {
-            // Creates Tokio runtime scope
-            tokio::runtime::Runtime::new().unwrap().block_on(async {
-                let mut epoch = epoch_days_ago(ARCHIVE_AFTER_DAYS.into());
-                loop {
-                    // Check if new day
-                    if epoch != epoch_days_ago(ARCHIVE_AFTER_DAYS.into()) {
-                        epoch = epoch_days_ago(ARCHIVE_AFTER_DAYS.into());
-                        println!("Checking archive task");
-                        archive(&config).await;
-                    }
-                    thread::sleep(Duration::from_secs(60));
+        handles.push(tokio::spawn(async move {
+            let mut epoch = epoch_days_ago(ARCHIVE_AFTER_DAYS.into());
+            loop {
+                // Check if new day
+                if epoch != epoch_days_ago(ARCHIVE_AFTER_DAYS.into()) {
+                    epoch = epoch_days_ago(ARCHIVE_AFTER_DAYS.into());
+                    println!("Checking archive task");
+                    archive(&config).await;
                 }
-            });
-        });
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+        }));
+    }
 
-        handles.push(handle);
+    // Everything that's read goes through the pipeline
+    let pipeline = Pipeline::start(&config);
+
+    // The files given at startup are tailed from where they end now
+    let mut tailed: HashMap<PathBuf, Arc<AtomicBool>> = HashMap::new();
+    for source in &config.nginx_sources {
+        match spawn_tailer(pipeline.sender(), source.clone(), false).await {
+            Ok((handle, stop)) => {
+                handles.push(handle);
+                tailed.insert(source.clone(), stop);
+            }
+            Err(e) => eprintln!("{}", format!("{}: {}", source.display(), e).red()),
+        }
     }
 
-    for mut lw in log_watchers {
-        let log_arc = Arc::clone(&log_arc);
+    if !config.nginx_globs.is_empty() {
         let config = config.clone();
-        let handle = thread::spawn(move || {
-            // Creates Tokio runtime scope
-            tokio::runtime::Runtime::new().unwrap().block_on(async {
-                lw.watch(&mut move |line: String| {
-                    let log_arc = Arc::clone(&log_arc);
-                    let logger = match Logger::from_line(&line) {
-                        Ok(l) => l,
-                        Err(e) => {
-                            eprintln!("{e}");
-                            eprintln!("Failed? {}", line);
-                            return LogWatcherAction::None;
-                        }
+        let lines = pipeline.sender();
+        handles.push(tokio::spawn(async move {
+            watch_globs(&config, lines, tailed).await
+        }));
+    }
+
+    for listener in config.syslog.clone() {
+        let config = config.clone();
+        let lines = pipeline.sender();
+        handles.push(tokio::spawn(async move {
+            let result = listener
+                .listen(lines, move |peer, raw| {
+                    let Some(message) = SyslogMessage::parse(raw) else {
+                        eprintln!("Not a syslog message from {}: {}", peer, raw);
+                        return None;
+                    };
+                    let tag = message
+                        .tag
+                        .as_ref()
+                        .and_then(|t| config.syslog_tags.get(t))
+                        .cloned()
+                        .unwrap_or_default();
+                    let source = match &message.tag {
+                        Some(tag) => format!("syslog://{}/{}", peer.ip(), tag),
+                        None => format!("syslog://{}", peer.ip()),
                     };
+                    Some(Line {
+                        text: message.message,
+                        source,
+                        offset: None,
+                        index: tag.index,
+                        format: tag.format,
+                    })
+                })
+                .await;
+            if let Err(e) = result {
+                eprintln!("{}", format!("{:#}", e).red());
+            }
+        }));
+    }
+
+    // Filter counters, whenever they changed
+    if !config.filter.is_empty() {
+        let config = config.clone();
+        handles.push(tokio::spawn(async move {
+            let mut reported = 0;
+            loop {
+                tokio::time::sleep(FILTER_REPORT_INTERVAL).await;
+                if config.filter.total_dropped() != reported {
+                    reported = config.filter.total_dropped();
+                    report_filter(&config);
+                }
+            }
+        }));
+    }
+
+    let mut pipe_handles = vec![];
+    for pipe in config.pipes.clone() {
+        let lines = pipeline.sender();
+        pipe_handles.push(tokio::spawn(async move {
+            if let Err(e) = read_pipe(&pipe, lines).await {
+                eprintln!("{}", format!("{}: {:#}", pipe, e).red());
+            }
+        }));
+    }
+
+    // The rest runs until it's stopped, so with only pipes to read it's done once they are
+    if !config.pipes.is_empty()
+        && config.nginx_sources.is_empty()
+        && config.nginx_globs.is_empty()
+        && config.syslog.is_empty()
+    {
+        for handle in pipe_handles {
+            let _ = handle.await;
+        }
+        let stats = pipeline.join().await;
+        let complete = finish(&config, &stats);
+        std::process::exit(if complete { 0 } else { 1 });
+    }
+
+    for handle in handles.into_iter().chain(pipe_handles) {
+        let _ = handle.await;
+    }
+}
+
+/// Tails a file in a task of its own, until the returned flag is set
+async fn spawn_tailer(
+    lines: Sender<Line>,
+    source: PathBuf,
+    from_start: bool,
+) -> Result<(JoinHandle<()>, Arc<AtomicBool>), Error> {
+    let mut tailer = Tailer::open(&source, from_start).await?;
+    let stop = tailer.stopper();
+    let handle = tokio::spawn(async move { tailer.watch(&lines).await });
+    Ok((handle, stop))
+}
+
+/// Looks for new and deleted files matching the glob patterns.
+/// New files are read from the start, as everything in them is new
+async fn watch_globs(
+    config: &Arc<Config>,
+    lines: Sender<Line>,
+    mut tailed: HashMap<PathBuf, Arc<AtomicBool>>,
+) {
+    // Rotated files can match the pattern under their new name, they've been read already.
+    // They're recognized by having been at a tailed path on the previous look
+    let mut rotated: HashMap<PathBuf, u64> = HashMap::new();
+    loop {
+        let previous: HashSet<u64> = tailed
+            .keys()
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|m| m.ino())
+            .collect();
+        tokio::time::sleep(GLOB_INTERVAL).await;
 
-                    {
-                        let mut log = log_arc.lock().unwrap();
-                        log.push(logger);
-                        if log.len() as u32 >= config.bulk_size {
-                            {
-                                let log_clone = log.clone();
-                                let config = config.clone();
-                                tokio::task::spawn(async move {
-                                    config.server.bulk(log_clone).await;
-                                });
-                                log.clear();
-                            }
-                        }
-                    }
-
-                    LogWatcherAction::None
-                });
-            });
+        let matches: HashSet<PathBuf> = config
+            .nginx_globs
+            .iter()
+            .flat_map(|pattern| glob_files(pattern))
+            .collect();
+
+        // Only the files that came from a pattern are let go of, the ones given by path are waited for
+        tailed.retain(|path, stop| {
+            if path.exists() || !config.nginx_globs.iter().any(|p| matches_glob(p, path)) {
+                return true;
+            }
+            println!("{} is gone, no longer tailing it", path.display());
+            stop.store(true, Ordering::Relaxed);
+            false
         });
 
-        handles.push(handle);
+        rotated.retain(|path, _| matches.contains(path));
+        for path in matches {
+            if tailed.contains_key(&path) {
+                continue;
+            }
+            let inode = fs::metadata(&path).map(|m| m.ino()).unwrap_or(0);
+            if rotated.get(&path) == Some(&inode) {
+                continue;
+            }
+            if previous.contains(&inode) {
+                rotated.insert(path, inode);
+                continue;
+            }
+            match spawn_tailer(lines.clone(), path.clone(), true).await {
+                Ok((_, stop)) => {
+                    println!("{} {}", "New log file:".green(), path.display());
+                    tailed.insert(path, stop);
+                }
+                Err(e) => eprintln!("{}", format!("{}: {}", path.display(), e).red()),
+            }
+        }
     }
+}
+
+/// Reads a pipe until it ends. Reading waits while the pipeline is full,
+/// so a large file piped in doesn't pile up in memory
+async fn read_pipe(pipe: &Pipe, lines: Sender<Line>) -> Result<(), Error> {
+    let mut reader = pipe.open().await?;
+    let source = pipe.source().to_string_lossy().to_string();
+    let mut offset: u64 = 0;
+    let mut count: u64 = 0;
+    let mut raw = vec![];
+    loop {
+        raw.clear();
+        let len = reader.read_until(b'\n', &mut raw).await?;
+        if len == 0 {
+            break;
+        }
+        let line_offset = offset;
+        offset += len as u64;
+
+        let text = String::from_utf8_lossy(&raw);
+        let text = text.trim_end_matches(['\r', '\n']).to_string();
+        if text.is_empty() {
+            continue;
+        }
+        count += 1;
 
-    for handle in handles {
-        handle.join().unwrap();
+        let line = Line {
+            text,
+            source: source.clone(),
+            offset: Some(line_offset),
+            index: None,
+            format: Format::Auto,
+        };
+        if lines.send(line).await.is_err() {
+            break;
+        }
+    }
+    println!("{} ended: {} lines read", pipe, count);
+    Ok(())
+}
+
+/// Prints how every output did, once everything is sent.
+/// Returns false if some documents never made it
+fn finish(config: &Config, stats: &Stats) -> bool {
+    if !config.filter.is_empty() {
+        report_filter(config);
+    }
+
+    let mut complete = true;
+    println!();
+    println!("Summary:");
+    println!(
+        "{} lines read, {} could not be parsed",
+        stats.lines(),
+        stats.failed()
+    );
+    for output in &config.outputs {
+        let health = output.health();
+        let queued = output.queued();
+        if queued == 0 && health.dropped_batches == 0 {
+            println!(
+                "{} {}: {} documents indexed",
+                "[✓]".green(),
+                output,
+                health.indexed
+            );
+        } else {
+            complete = false;
+            println!(
+                "{} {}: {} documents indexed, {} documents not sent ({} batches dropped)",
+                "[X]".red(),
+                output,
+                health.indexed,
+                queued,
+                health.dropped_batches
+            );
+        }
+    }
+    complete
+}
+
+fn report_filter(config: &Config) {
+    println!("Filtered:");
+    for line in config.filter.report() {
+        println!("  {}", line);
     }
 }
 
 async fn archive(config: &Config) {
     if let Some(ap) = config.archive_folder.clone() {
-        let count = config.server.count_before(ARCHIVE_AFTER_DAYS).await;
+        // All outputs receive the same documents, so only the first cluster
+        // is archived. The others just get the old documents removed
+        let mut servers = config.outputs.iter().filter_map(|o| o.server());
+        let server = match servers.next() {
+            Some(s) => s,
+            None => return,
+        };
+        let count = server.count_before(ARCHIVE_AFTER_DAYS).await;
 
         if count > 0 {
             println!("Documents to archive: {}", count);
 
-            let response = config
-                .server
+            let response = server
                 .archive(&ap, &config.archive_file_prefix, ARCHIVE_AFTER_DAYS)
                 .await;
             if let Err(r) = response {
@@ -134,5 +379,18 @@ async fn archive(config: &Config) {
                 ARCHIVE_AFTER_DAYS
             );
         }
+
+        for server in servers {
+            if server.count_before(ARCHIVE_AFTER_DAYS).await > 0 {
+                println!("Deleting old documents from {}", server);
+                server.delete_before(ARCHIVE_AFTER_DAYS).await;
+            }
+        }
+
+        for server in config.outputs.iter().filter_map(|o| o.server()) {
+            if let Err(e) = server.delete_errors_before(ARCHIVE_AFTER_DAYS).await {
+                eprintln!("WARNING: {}", e);
+            }
+        }
     }
 }
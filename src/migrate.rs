@@ -0,0 +1,426 @@
+use anyhow::{bail, Context, Error, Result};
+use colored::Colorize;
+use serde_json::{json, Map, Value};
+use std::io::{self, Write};
+use std::{fmt, time};
+
+use crate::logger::{Logger, SECONDS_BELOW};
+use crate::server::Server;
+
+/// A single difference between the live mapping and the one nginx-es expects
+pub enum Change {
+    /// Missing from the live mapping, can be added in place
+    Added(String, Value),
+    /// Exists with a different type or format, needs a new index
+    Changed(String, Value, Value),
+    /// Not expected by nginx-es, needs a new index to get rid of it
+    Removed(String),
+}
+
+impl Change {
+    pub fn is_compatible(&self) -> bool {
+        matches!(self, Change::Added(..))
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Added(field, to) => write!(f, "+ {}: {}", field, to),
+            Change::Changed(field, from, to) => write!(f, "~ {}: {} → {}", field, from, to),
+            Change::Removed(field) => write!(f, "- {}", field),
+        }
+    }
+}
+
+/// Compares the live properties with the expected ones
+pub fn diff(live: &Map<String, Value>, expected: &Map<String, Value>) -> Vec<Change> {
+    let mut changes = vec![];
+    for (field, to) in expected {
+        match live.get(field) {
+            None => changes.push(Change::Added(field.clone(), to.clone())),
+            Some(from) if from != to => {
+                changes.push(Change::Changed(field.clone(), from.clone(), to.clone()))
+            }
+            Some(_) => {}
+        }
+    }
+    for field in live.keys() {
+        if !expected.contains_key(field) {
+            changes.push(Change::Removed(field.clone()));
+        }
+    }
+    changes
+}
+
+/// Brings the mapping of the server up to date with the one nginx-es expects.
+/// New fields are added in place, anything else needs a new index.
+/// With dry_run, it only reports what it would do
+pub async fn migrate(server: &Server, dry_run: bool) -> Result<(), Error> {
+    let expected = Logger::expected_properties(server.index_options().data_stream);
+    let (concrete, live) = live_properties(server).await?;
+    let changes = diff(&live, &expected);
+
+    println!("Mapping of {}:", server);
+    if changes.is_empty() {
+        println!("{}", "  Already up to date, nothing to migrate".green());
+        return Ok(());
+    }
+    for change in &changes {
+        if change.is_compatible() {
+            println!("  {}", change.to_string().green());
+        } else {
+            println!("  {}", change.to_string().red());
+        }
+    }
+    let added: Map<String, Value> = changes
+        .iter()
+        .filter_map(|c| match c {
+            Change::Added(field, to) => Some((field.clone(), to.clone())),
+            _ => None,
+        })
+        .collect();
+    let incompatible = changes.iter().any(|c| !c.is_compatible());
+    // A regular index has to be replaced to get incompatible changes in
+    let replacing = incompatible && !server.index_options().uses_template();
+
+    println!();
+    println!("Plan:");
+    if server.index_options().uses_template() || server.index_options().ilm {
+        println!("  Update index template {}", server.index());
+    }
+    if !added.is_empty() {
+        println!("  Add {} field(s) to the existing indices", added.len());
+    }
+    let target = next_index(server.index(), &concrete);
+    if incompatible {
+        if server.index_options().data_stream {
+            println!(
+                "  Roll over data stream {}, new backing index gets the new mapping",
+                server.index()
+            );
+        } else if server.index_options().rollover.is_some() {
+            println!("  Incompatible changes take effect from the next rolling index on, existing indices are left as is");
+        } else {
+            println!("  Create {} with the new mapping", target);
+            println!("  Reindex {:?} into {}", concrete, target);
+            println!(
+                "  Replace {:?} with {} behind the alias {}",
+                concrete,
+                target,
+                server.index()
+            );
+        }
+    }
+
+    if dry_run {
+        println!();
+        println!("{}", "Dry run, nothing was changed".yellow());
+        return Ok(());
+    }
+
+    if replacing {
+        println!();
+        println!(
+            "  Make sure nginx-es is not writing to {} while migrating.",
+            server.index()
+        );
+        println!("  The old indices are deleted once everything is reindexed.");
+        println!("  Do you want to continue ?");
+        if !server.index_options().prompt.confirm()? {
+            bail!("Cancelled due to user input");
+        }
+    }
+
+    if server.index_options().uses_template() || server.index_options().ilm {
+        Logger::put_template(server).await?;
+        println!("Updated index template {}", server.index());
+    }
+    // Only worth adding in place if the indices aren't replaced anyway
+    if !added.is_empty() && !replacing {
+        put_mapping(server, added).await?;
+        println!("Added the new field(s)");
+    }
+    if incompatible && server.index_options().data_stream {
+        acknowledged(
+            server
+                .http()
+                .post(format!(
+                    "{}/{}/_rollover",
+                    server.get_host(),
+                    server.index()
+                ))
+                .send()
+                .await?
+                .json()
+                .await?,
+        )?;
+        println!("Rolled over data stream {}", server.index());
+    }
+    if replacing {
+        reindex(server, &concrete, &target).await?;
+    }
+
+    println!("{}", "Migration done".green());
+    Ok(())
+}
+
+/// The indices behind the index (or alias), and the properties of the newest one.
+/// For rolling indices and data streams it's the properties of the index template
+async fn live_properties(server: &Server) -> Result<(Vec<String>, Map<String, Value>), Error> {
+    if server.index_options().uses_template() {
+        let url = format!("{}/_index_template/{}", server.get_host(), server.index());
+        let j: Value = server.http().get(url).send().await?.json().await?;
+        let properties = j["index_templates"]
+            .as_array()
+            .and_then(|t| t.iter().find(|t| t["name"] == server.index()))
+            .and_then(|t| t["index_template"]["template"]["mappings"]["properties"].as_object())
+            .cloned()
+            .unwrap_or_default();
+        return Ok((vec![], properties));
+    }
+
+    let url = format!("{}/_mapping", server.get_url());
+    let j: Value = server.http().get(url).send().await?.json().await?;
+    if j.get("error").is_some() {
+        bail!("Unable to get the mapping: {}", j["error"]);
+    }
+    let indices = j
+        .as_object()
+        .with_context(|| format!("Unexpected response: {}", j))?;
+    let properties = indices
+        .values()
+        .next_back()
+        .and_then(|i| i["mappings"]["properties"].as_object())
+        .cloned()
+        .unwrap_or_default();
+    Ok((indices.keys().cloned().collect(), properties))
+}
+
+/// The next generation of the index, logger-000002 → logger-000003.
+/// Only `<index>-<number>` counts, not dated indices like logger-2023.10 or another index like logger2-000005
+fn next_index(index: &str, concrete: &[String]) -> String {
+    let generation = concrete
+        .iter()
+        .filter_map(|c| c.strip_prefix(index)?.strip_prefix('-'))
+        .filter(|n| n.bytes().all(|b| b.is_ascii_digit()))
+        .filter_map(|n| n.parse::<u32>().ok())
+        .max()
+        .unwrap_or(0);
+    format!("{}-{:06}", index, generation + 1)
+}
+
+async fn put_mapping(server: &Server, added: Map<String, Value>) -> Result<(), Error> {
+    let res: Value = server
+        .http()
+        .put(format!("{}/_mapping", server.get_url()))
+        .json(&json!({ "properties": added }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    acknowledged(res)
+}
+
+/// Documents from before milliseconds were used have the time in epoch seconds
+fn seconds_to_millis() -> String {
+    format!(
+        "if (ctx._source.time instanceof Number && ctx._source.time < {}L) {{ ctx._source.time = ctx._source.time * 1000L }}",
+        SECONDS_BELOW
+    )
+}
+
+/// Copies everything into a new index, then atomically swaps the old ones out for an alias
+async fn reindex(server: &Server, sources: &[String], target: &str) -> Result<(), Error> {
+    let host = server.get_host();
+    acknowledged(
+        server
+            .http()
+            .put(format!("{}/{}", host, target))
+            .json(&Logger::new_index_body(server))
+            .send()
+            .await?
+            .json()
+            .await?,
+    )?;
+    println!("Created {}", target);
+
+    let res: Value = server
+        .http()
+        .post(format!("{}/_reindex?wait_for_completion=false", host))
+        .json(&json!({
+            "source": { "index": sources },
+            "dest": { "index": target },
+            "script": { "source": seconds_to_millis() }
+        }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let task = res["task"]
+        .as_str()
+        .with_context(|| format!("Failed to start reindexing: {}", res))?;
+
+    print!("Reindexing");
+    loop {
+        tokio::time::sleep(time::Duration::from_secs(5)).await;
+        let status: Value = server
+            .http()
+            .get(format!("{}/_tasks/{}", host, task))
+            .send()
+            .await?
+            .json()
+            .await?;
+        if status["completed"].as_bool() == Some(true) {
+            println!();
+            let response = &status["response"];
+            if status.get("error").is_some()
+                || response["failures"]
+                    .as_array()
+                    .is_some_and(|f| !f.is_empty())
+            {
+                bail!(
+                    "Reindexing failed, {} is left as is: {}",
+                    server.index(),
+                    status
+                );
+            }
+            println!("Reindexed {} documents", response["total"]);
+            break;
+        }
+        print!(".");
+        let _ = io::stdout().flush();
+    }
+
+    let mut actions = vec![json!({
+        "add": { "index": target, "alias": server.index(), "is_write_index": true }
+    })];
+    for source in sources {
+        actions.push(json!({ "remove_index": { "index": source } }));
+    }
+    acknowledged(
+        server
+            .http()
+            .post(format!("{}/_aliases", host))
+            .json(&json!({ "actions": actions }))
+            .send()
+            .await?
+            .json()
+            .await?,
+    )?;
+    println!("{} is now an alias for {}", server.index(), target);
+    Ok(())
+}
+
+fn acknowledged(res: Value) -> Result<(), Error> {
+    if res["acknowledged"].as_bool() != Some(true) {
+        bail!("{}", res);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    fn names(indices: &[&str]) -> Vec<String> {
+        indices.iter().map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn changes() {
+        let expected = properties(json!({
+            "client_ip": { "type": "ip" },
+            "status_code": { "type": "short" },
+            "time": { "type": "date", "format": "epoch_millis" }
+        }));
+        assert!(diff(&expected, &expected).is_empty());
+
+        let live = properties(json!({
+            "alt_ip": { "type": "ip" },
+            "status_code": { "type": "short" },
+            "time": { "type": "date", "format": "epoch_second" }
+        }));
+        let changes: Vec<String> = diff(&live, &expected)
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                r#"+ client_ip: {"type":"ip"}"#,
+                r#"~ time: {"format":"epoch_second","type":"date"} → {"format":"epoch_millis","type":"date"}"#,
+                "- alt_ip",
+            ]
+        );
+        let compatible: Vec<bool> = diff(&live, &expected)
+            .iter()
+            .map(|c| c.is_compatible())
+            .collect();
+        assert_eq!(compatible, [true, false, false]);
+    }
+
+    #[test]
+    fn nested_properties() {
+        let expected = properties(json!({
+            "geo": { "properties": {
+                "country": { "type": "keyword" },
+                "location": { "type": "geo_point" }
+            }}
+        }));
+        // Key order doesn't matter, the objects are compared as a whole
+        let same = properties(json!({
+            "geo": { "properties": {
+                "location": { "type": "geo_point" },
+                "country": { "type": "keyword" }
+            }}
+        }));
+        assert!(diff(&same, &expected).is_empty());
+
+        // A change deep inside replaces the whole field
+        let live = properties(json!({
+            "geo": { "properties": {
+                "country": { "type": "keyword" },
+                "location": { "type": "keyword" }
+            }}
+        }));
+        let changes = diff(&live, &expected);
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(&changes[0], Change::Changed(field, ..) if field == "geo"));
+        assert!(!changes[0].is_compatible());
+    }
+
+    #[test]
+    fn generations() {
+        assert_eq!(next_index("logger", &[]), "logger-000001");
+        // The original index, before it was ever replaced
+        assert_eq!(next_index("logger", &names(&["logger"])), "logger-000001");
+        assert_eq!(
+            next_index("logger", &names(&["logger-000002"])),
+            "logger-000003"
+        );
+        assert_eq!(
+            next_index("logger", &names(&["logger-000009", "logger-000010"])),
+            "logger-000011"
+        );
+        assert_eq!(
+            next_index("logger", &names(&["logger-999999"])),
+            "logger-1000000"
+        );
+    }
+
+    #[test]
+    fn other_indices_are_no_generation() {
+        // Months and days of rolling indices aren't generations
+        let dated = names(&["logger-2023.10", "logger-2023.11.16"]);
+        assert_eq!(next_index("logger", &dated), "logger-000001");
+        let others = names(&["logger2-000005", "logger-web-000007", "logger-000002-old"]);
+        assert_eq!(next_index("logger", &others), "logger-000001");
+        let mixed = names(&["logger-2023.12", "logger-000004"]);
+        assert_eq!(next_index("logger", &mixed), "logger-000005");
+    }
+}
@@ -0,0 +1,241 @@
+use anyhow::{Context, Error, Result};
+use colored::Colorize;
+use reqwest::Url;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+use crate::document::Document;
+use crate::server::Server;
+
+/// How many failed batches an output keeps around for retrying,
+/// before it holds up the new ones
+const MAX_RETRY_BATCHES: usize = 20;
+
+/// How long a full retry queue waits before it's sent again
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Where the parsed documents end up
+pub enum Destination {
+    Elasticsearch(Box<Server>),
+    File(PathBuf),
+}
+
+/// Counters describing how well an output is doing
+#[derive(Default, Clone)]
+pub struct Health {
+    pub indexed: u64,
+    pub failed_batches: u64,
+    /// Left out while the output was failing and too far behind
+    pub dropped_batches: u64,
+    pub consecutive_failures: u32,
+}
+
+impl Health {
+    pub fn is_healthy(&self) -> bool {
+        self.consecutive_failures == 0
+    }
+}
+
+/// A single destination with its own bulk size and retry queue.
+/// Every output gets fed the same parsed stream.
+pub struct Output {
+    pub destination: Destination,
+    pub bulk_size: u32,
+    retry: Mutex<VecDeque<Vec<Document>>>,
+    health: Mutex<Health>,
+}
+
+/// Reads the `bulk_size` query parameter from an output url
+/// http://127.0.0.1:9200/logger?bulk_size=200 → Some(200)
+pub fn bulk_size_from_url(url: &str) -> Option<u32> {
+    let parsed_url = Url::parse(url).ok()?;
+    parsed_url
+        .query_pairs()
+        .find(|(k, _)| k == "bulk_size")
+        .and_then(|(_, v)| v.parse::<u32>().ok())
+}
+
+/// Checks if the string is a file output, eg. file:///var/log/nginx-es/out.ndjson
+pub fn is_file_url(str: &str) -> bool {
+    str.starts_with("file://")
+}
+
+impl Output {
+    pub fn new(destination: Destination, bulk_size: u32) -> Self {
+        Output {
+            destination,
+            bulk_size,
+            retry: Mutex::new(VecDeque::new()),
+            health: Mutex::new(Health::default()),
+        }
+    }
+
+    /// Creates a file output from a file:// url
+    pub fn from_file_url(url: &str, bulk_size: u32) -> Result<Self, Error> {
+        let parsed_url = Url::parse(url)?;
+        let path = parsed_url
+            .to_file_path()
+            .ok()
+            .with_context(|| format!("Not a valid file path: {}", url))?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Unable to open {:?} for writing", path))?;
+        Ok(Output::new(Destination::File(path), bulk_size))
+    }
+
+    pub fn server(&self) -> Option<&Server> {
+        match &self.destination {
+            Destination::Elasticsearch(s) => Some(s),
+            Destination::File(_) => None,
+        }
+    }
+
+    pub fn health(&self) -> Health {
+        self.health.lock().unwrap().clone()
+    }
+
+    /// How many documents are waiting in the retry queue
+    pub fn queued(&self) -> usize {
+        self.retry.lock().unwrap().iter().map(Vec::len).sum()
+    }
+
+    /// Sends the queued retries first, then the new batch.
+    /// Anything that fails is put back in the retry queue
+    pub async fn flush(&self, batch: Vec<Document>) {
+        let mut batches = self.take_retries();
+        batches.push(batch);
+        self.send_all(batches).await;
+    }
+
+    /// Waits until the retry queue has room again, sending it every now and then.
+    /// Stops waiting once `done` tells nothing more is coming, what's left is tried once more then
+    pub async fn wait_for_room(&self, done: impl Fn() -> bool) {
+        if !self.is_full() || done() {
+            return;
+        }
+        println!(
+            "{}",
+            format!("[{}] Retry queue is full, holding off new batches", self).red()
+        );
+        while self.is_full() && !done() {
+            tokio::time::sleep(RETRY_INTERVAL).await;
+            let batches = self.take_retries();
+            self.send_all(batches).await;
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.retry.lock().unwrap().len() >= MAX_RETRY_BATCHES
+    }
+
+    fn take_retries(&self) -> Vec<Vec<Document>> {
+        let batches: Vec<Vec<Document>> = self.retry.lock().unwrap().drain(..).collect();
+        if !batches.is_empty() {
+            println!("[{}] Retrying {} queued batch(es)", self, batches.len());
+        }
+        batches
+    }
+
+    async fn send_all(&self, batches: Vec<Vec<Document>>) {
+        let mut batches = batches.into_iter();
+        while let Some(batch) = batches.next() {
+            match self.send(&batch).await {
+                Ok(count) => self.success(count),
+                Err(e) => {
+                    self.failure(&e);
+                    // No point hammering a dead destination with the rest
+                    self.requeue(batch);
+                    for batch in batches.by_ref() {
+                        self.requeue(batch);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send(&self, batch: &[Document]) -> Result<u32, Error> {
+        match &self.destination {
+            Destination::Elasticsearch(server) => server.bulk(batch.to_vec()).await,
+            Destination::File(path) => {
+                let mut lines = String::new();
+                for elm in batch {
+                    lines.push_str(&serde_json::to_string(elm)?);
+                    lines.push('\n');
+                }
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+                file.write_all(lines.as_bytes()).await?;
+                file.flush().await?;
+                Ok(batch.len() as u32)
+            }
+        }
+    }
+
+    /// Counts a batch that's given up on before it got to the output
+    pub fn drop_batch(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.dropped_batches += 1;
+        println!(
+            "{}",
+            format!(
+                "[{}] Too far behind while failing, dropped a batch ({} so far)",
+                self, health.dropped_batches
+            )
+            .red()
+        );
+    }
+
+    fn requeue(&self, batch: Vec<Document>) {
+        self.retry.lock().unwrap().push_back(batch);
+    }
+
+    fn success(&self, count: u32) {
+        let mut health = self.health.lock().unwrap();
+        if !health.is_healthy() {
+            println!(
+                "{}",
+                format!(
+                    "[{}] Recovered after {} failed attempt(s) ({} failed, {} dropped batches so far)",
+                    self, health.consecutive_failures, health.failed_batches, health.dropped_batches
+                )
+                .green()
+            );
+        }
+        health.indexed += count as u64;
+        health.consecutive_failures = 0;
+        println!("[{}] {} documents in total", self, health.indexed);
+    }
+
+    fn failure(&self, e: &Error) {
+        let mut health = self.health.lock().unwrap();
+        health.failed_batches += 1;
+        health.consecutive_failures += 1;
+        println!(
+            "{}",
+            format!(
+                "[{}] Unhealthy ({} consecutive failure(s)): {}",
+                self, health.consecutive_failures, e
+            )
+            .red()
+        );
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.destination {
+            Destination::Elasticsearch(s) => write!(f, "{}", s),
+            Destination::File(p) => write!(f, "file://{}", p.display()),
+        }
+    }
+}
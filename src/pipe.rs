@@ -0,0 +1,83 @@
+use anyhow::{Context, Error, Result};
+use std::fmt;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufRead, BufReader};
+
+/// A source that's read once from start to end, instead of being tailed
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pipe {
+    /// `-`, for `zcat access.log.gz | nginx-es - ...`
+    Stdin,
+    /// A named pipe, made with mkfifo
+    Fifo(PathBuf),
+}
+
+/// Checks if the path is a named pipe. Those can't be peeked at without eating the lines
+pub fn is_fifo(path: &Path) -> bool {
+    path.metadata().is_ok_and(|m| m.file_type().is_fifo())
+}
+
+impl Pipe {
+    /// Opening a FIFO waits until something opens it for writing
+    pub async fn open(&self) -> Result<Box<dyn AsyncBufRead + Send + Unpin>, Error> {
+        Ok(match self {
+            Pipe::Stdin => Box::new(BufReader::new(tokio::io::stdin())),
+            Pipe::Fifo(path) => Box::new(BufReader::new(
+                tokio::fs::File::open(path)
+                    .await
+                    .with_context(|| format!("Unable to open {:?}", path))?,
+            )),
+        })
+    }
+
+    /// What the document ids are made from, together with the offset
+    pub fn source(&self) -> PathBuf {
+        match self {
+            Pipe::Stdin => PathBuf::from("-"),
+            Pipe::Fifo(path) => path.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Pipe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pipe::Stdin => write!(f, "stdin"),
+            Pipe::Fifo(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncBufReadExt;
+
+    #[tokio::test]
+    async fn fifo_until_closed() {
+        let path = std::env::temp_dir().join(format!("nginx-es-test-{}.fifo", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert!(!is_fifo(&path));
+        let made = std::process::Command::new("mkfifo")
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(made.success());
+        assert!(is_fifo(&path));
+        assert!(!is_fifo(Path::new("Cargo.toml")));
+
+        let writer = {
+            let path = path.clone();
+            std::thread::spawn(move || std::fs::write(path, "a\nb\n").unwrap())
+        };
+        let pipe = Pipe::Fifo(path.clone());
+        let mut lines = pipe.open().await.unwrap().lines();
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("a"));
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("b"));
+        assert_eq!(lines.next_line().await.unwrap(), None);
+        writer.join().unwrap();
+        assert_eq!(pipe.source(), path);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::config::Config;
+use crate::document::{Document, Format};
+use crate::logger::IdStrategy;
+use crate::output::Output;
+
+const DEFAULT_QUEUE_SIZE: usize = 10000;
+const DEFAULT_CONCURRENCY: usize = 2;
+
+/// How many batches the batcher keeps for an output that can't take them right now
+const MAX_OVERFLOW_BATCHES: usize = 100;
+
+/// How often a full output is checked on while the batcher waits for it,
+/// and how often the batches it couldn't take yet are handed over again
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many lines can wait to be parsed, and how many bulk requests every output sends at once
+#[derive(Clone, Copy, Debug)]
+pub struct PipelineOptions {
+    pub workers: usize,
+    pub queue_size: usize,
+    pub concurrency: usize,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        PipelineOptions {
+            workers: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            queue_size: DEFAULT_QUEUE_SIZE,
+            concurrency: DEFAULT_CONCURRENCY,
+        }
+    }
+}
+
+/// A line as it's read, before it's parsed
+pub struct Line {
+    pub text: String,
+    /// A path, `-` for stdin or syslog://ip/tag
+    pub source: String,
+    /// Where the line starts in the source, when there is such a thing
+    pub offset: Option<u64>,
+    /// Overrides the index set for the source
+    pub index: Option<String>,
+    pub format: Format,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    lines: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl Stats {
+    pub fn lines(&self) -> u64 {
+        self.lines.load(Ordering::Relaxed)
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+/// Sources → parser workers → batcher → a sender per output, with a bounded queue in between each.
+/// When the outputs can't keep up the queues fill, and the sources wait before reading on
+pub struct Pipeline {
+    lines: mpsc::Sender<Line>,
+    stats: Arc<Stats>,
+    batcher: JoinHandle<()>,
+}
+
+impl Pipeline {
+    /// Starts the workers and the batcher, on the runtime it's called from
+    pub fn start(config: &Arc<Config>) -> Self {
+        let options = config.pipeline;
+        let (lines, receiver) = mpsc::channel::<Line>(options.queue_size);
+        let (documents, batched) = mpsc::channel::<Document>(options.queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let stats = Arc::new(Stats::default());
+
+        // Parsing is all cpu, so the workers get threads of their own
+        for _ in 0..options.workers {
+            let config = config.clone();
+            let receiver = receiver.clone();
+            let documents = documents.clone();
+            let stats = stats.clone();
+            tokio::task::spawn_blocking(move || loop {
+                let Some(line) = receiver.lock().unwrap().blocking_recv() else {
+                    return;
+                };
+                stats.lines.fetch_add(1, Ordering::Relaxed);
+                let Some(document) = process(&config, line, &stats) else {
+                    continue;
+                };
+                if documents.blocking_send(document).is_err() {
+                    return;
+                }
+            });
+        }
+
+        let batcher = tokio::task::spawn(batch(config.clone(), batched));
+        Pipeline {
+            lines,
+            stats,
+            batcher,
+        }
+    }
+
+    /// Where the sources send their lines. Sending waits while the queue is full
+    pub fn sender(&self) -> mpsc::Sender<Line> {
+        self.lines.clone()
+    }
+
+    /// Waits until all the senders are gone and everything they sent is sent on to the outputs
+    pub async fn join(self) -> Arc<Stats> {
+        drop(self.lines);
+        if let Err(e) = self.batcher.await {
+            eprintln!("Batcher failed: {}", e);
+        }
+        self.stats
+    }
+}
+
+/// Parses, enriches, filters and anonymizes a line.
+/// Returns None for lines that aren't indexed
+fn process(config: &Config, line: Line, stats: &Stats) -> Option<Document> {
+    let mut document = match Document::parse_as(&line.text, line.format, &config.json_fields) {
+        Ok(d) => d,
+        Err(e) => {
+            stats.failed.fetch_add(1, Ordering::Relaxed);
+            eprintln!("{e}");
+            eprintln!("Failed? {}", line.text);
+            return None;
+        }
+    };
+    config.label(&mut document, &line.source);
+    if line.index.is_some() {
+        document.set_index(line.index);
+    }
+
+    match &mut document {
+        Document::Access(logger) => {
+            config.enricher.enrich(logger);
+            if !config.filter.allows(logger) {
+                return None;
+            }
+            // Last, the real address is still needed for GeoIP and the filters
+            logger.anonymize(&config.anonymizer);
+        }
+        Document::Error(error) => error.anonymize(&config.anonymizer),
+    }
+
+    let strategy = match (line.offset, config.id_strategy) {
+        // There's no offset to tell identical lines apart, so elasticsearch picks the id
+        (None, IdStrategy::Line) => IdStrategy::Auto,
+        (_, s) => s,
+    };
+    // A hash of the raw line would give the real addresses away to anyone trying them all,
+    // so it's made from what gets indexed instead
+    let text = match config.anonymizer.is_enabled() {
+        true => serde_json::to_string(&document).unwrap_or_default(),
+        false => line.text,
+    };
+    document.assign_id(
+        strategy,
+        &text,
+        Path::new(&line.source),
+        line.offset.unwrap_or(0),
+    );
+    Some(document)
+}
+
+/// Fills a buffer per output, handing every one that reaches its bulk size to the sender of the output.
+/// Once no more documents are coming, what's left is sent as is
+async fn batch(config: Arc<Config>, mut documents: mpsc::Receiver<Document>) {
+    let concurrency = config.pipeline.concurrency;
+    let mut senders = JoinSet::new();
+    let mut lanes: Vec<Lane> = config
+        .outputs
+        .iter()
+        .map(|output| Lane::start(output.clone(), concurrency, &mut senders))
+        .collect();
+
+    let mut buffers: Vec<Vec<Document>> = vec![vec![]; config.outputs.len()];
+    loop {
+        // What the outputs couldn't take is handed over once they can, even when nothing new comes in
+        let document = match lanes.iter().any(|l| !l.overflow.is_empty()) {
+            true => match tokio::time::timeout(HEALTH_CHECK_INTERVAL, documents.recv()).await {
+                Ok(document) => document,
+                Err(_) => {
+                    for lane in lanes.iter_mut() {
+                        lane.make_room(MAX_OVERFLOW_BATCHES).await;
+                    }
+                    continue;
+                }
+            },
+            false => documents.recv().await,
+        };
+        let Some(document) = document else {
+            break;
+        };
+        // Every output gets its own copy of the parsed line
+        for (lane, buffer) in lanes.iter_mut().zip(&mut buffers) {
+            buffer.push(document.clone());
+            if buffer.len() as u32 >= lane.output.bulk_size {
+                lane.push(std::mem::take(buffer)).await;
+            }
+        }
+    }
+
+    for (mut lane, buffer) in lanes.into_iter().zip(buffers) {
+        if !buffer.is_empty() {
+            lane.overflow.push_back(buffer);
+        }
+        lane.make_room(0).await;
+    }
+    while senders.join_next().await.is_some() {}
+}
+
+/// The way to the sender of one output, with the batches it couldn't take yet.
+/// A slow output holds up the batcher once it's too far behind, which slows down the reading.
+/// A failing one doesn't, its oldest batches are dropped instead so the other outputs keep going
+struct Lane {
+    output: Arc<Output>,
+    queue: mpsc::Sender<Vec<Document>>,
+    overflow: VecDeque<Vec<Document>>,
+}
+
+impl Lane {
+    fn start(output: Arc<Output>, concurrency: usize, senders: &mut JoinSet<()>) -> Self {
+        let (queue, batches) = mpsc::channel::<Vec<Document>>(concurrency);
+        senders.spawn(send(output.clone(), batches, concurrency));
+        Lane {
+            output,
+            queue,
+            overflow: VecDeque::new(),
+        }
+    }
+
+    async fn push(&mut self, batch: Vec<Document>) {
+        self.overflow.push_back(batch);
+        self.make_room(MAX_OVERFLOW_BATCHES).await;
+    }
+
+    /// Hands over what the sender takes without waiting,
+    /// then waits for or drops batches until at most `limit` are left
+    async fn make_room(&mut self, limit: usize) {
+        while let Some(batch) = self.overflow.pop_front() {
+            match self.queue.try_send(batch) {
+                Ok(()) => {}
+                Err(TrySendError::Full(batch)) => {
+                    self.overflow.push_front(batch);
+                    break;
+                }
+                Err(TrySendError::Closed(_)) => return self.overflow.clear(),
+            }
+        }
+
+        while self.overflow.len() > limit {
+            if !self.output.health().is_healthy() {
+                self.overflow.pop_front();
+                self.output.drop_batch();
+                continue;
+            }
+            // Working but slow, so it's worth waiting for as long as it stays that way
+            match tokio::time::timeout(HEALTH_CHECK_INTERVAL, self.queue.reserve()).await {
+                Ok(Ok(permit)) => permit.send(self.overflow.pop_front().unwrap()),
+                Ok(Err(_)) => return self.overflow.clear(),
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+/// Sends the batches of one output, up to `concurrency` bulk requests at once.
+/// A slow or failing output only uses up its own queue, until its retry queue is full
+async fn send(output: Arc<Output>, mut batches: mpsc::Receiver<Vec<Document>>, concurrency: usize) {
+    let permits = Arc::new(Semaphore::new(concurrency));
+    let mut sending = JoinSet::new();
+    while let Some(batch) = batches.recv().await {
+        output.wait_for_room(|| batches.is_closed()).await;
+        let permit = permits.clone().acquire_owned().await.unwrap();
+        let output = output.clone();
+        sending.spawn(async move {
+            output.flush(batch).await;
+            drop(permit);
+        });
+        while sending.try_join_next().is_some() {}
+    }
+    while sending.join_next().await.is_some() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anonymize::{Anonymizer, IpMode};
+    use crate::config::Command;
+    use crate::json::JsonFields;
+    use crate::logger::{line_id, Logger};
+    use crate::output::Destination;
+    use crate::source::Labels;
+
+    fn config(anonymizer: Anonymizer) -> Config {
+        Config {
+            command: Command::Run,
+            nginx_sources: vec![],
+            nginx_globs: vec![],
+            pipes: vec![],
+            outputs: vec![],
+            archive_folder: None,
+            archive_file_prefix: String::new(),
+            enricher: Arc::default(),
+            filter: Arc::default(),
+            anonymizer: Arc::new(anonymizer),
+            id_strategy: IdStrategy::Line,
+            json_fields: JsonFields::default(),
+            syslog: vec![],
+            syslog_tags: Default::default(),
+            labels: Labels::default(),
+            sources: vec![],
+            pipeline: PipelineOptions::default(),
+        }
+    }
+
+    fn line(ip: &str) -> Line {
+        Line {
+            text: format!(
+                r#"{} - - [17/Oct/2026:10:00:00 +0000] "example.com" "GET / HTTP/1.1" 200 12 "-" "curl""#,
+                ip
+            ),
+            source: "/var/log/nginx/access.log".to_string(),
+            offset: Some(1024),
+            index: None,
+            format: Format::Access,
+        }
+    }
+
+    #[test]
+    fn line_ids_dont_give_anonymized_addresses_away() {
+        let stats = Stats::default();
+        let id = |config: &Config, ip: &str| {
+            let document = process(config, line(ip), &stats).unwrap();
+            document.id().unwrap().to_string()
+        };
+        let raw = line("1.2.3.4");
+        let raw_id = line_id(&raw.text, Path::new(&raw.source), 1024);
+        assert_eq!(id(&config(Anonymizer::default()), "1.2.3.4"), raw_id);
+
+        let mut anonymizer = Anonymizer::default();
+        anonymizer.ips = IpMode::Truncate;
+        let truncating = config(anonymizer);
+        assert_ne!(id(&truncating, "1.2.3.4"), raw_id);
+        // Nothing tells the addresses apart once they're truncated
+        assert_eq!(id(&truncating, "1.2.3.4"), id(&truncating, "1.2.3.5"));
+
+        let mut anonymizer = Anonymizer::default();
+        anonymizer.ips = IpMode::Hmac(b"secret".to_vec());
+        assert_ne!(id(&config(anonymizer), "1.2.3.4"), raw_id);
+    }
+
+    #[tokio::test]
+    async fn failing_output_does_not_hold_up_the_others() {
+        let dir = std::env::temp_dir().join(format!("nginx-es-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let working = Arc::new(Output::new(Destination::File(dir.join("out.ndjson")), 1));
+        // A directory can't be appended to
+        let failing = Arc::new(Output::new(Destination::File(dir.clone()), 1));
+
+        let mut senders = JoinSet::new();
+        let mut lanes = [
+            Lane::start(working.clone(), 2, &mut senders),
+            Lane::start(failing.clone(), 2, &mut senders),
+        ];
+        let document = Document::Access(Box::new(Logger::dummy_data()));
+        let pushing = async {
+            for _ in 0..200 {
+                for lane in lanes.iter_mut() {
+                    lane.push(vec![document.clone()]).await;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(30), pushing)
+            .await
+            .expect("The failing output held up the batcher");
+        for mut lane in lanes {
+            tokio::time::timeout(Duration::from_secs(30), lane.make_room(0))
+                .await
+                .expect("The failing output held up the batcher");
+        }
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while working.health().indexed < 200 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("The working output didn't get everything");
+        assert_eq!(working.health().dropped_batches, 0);
+        assert!(!failing.health().is_healthy());
+        assert!(failing.health().dropped_batches > 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::{Context, Error, Result};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+/// The proxies in front of nginx, whose entries in X-Forwarded-For can be skipped
+#[derive(Clone)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+}
+
+impl Default for TrustedProxies {
+    /// Only loopback, like a proxy on the same machine
+    fn default() -> Self {
+        TrustedProxies {
+            nets: vec!["127.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()],
+        }
+    }
+}
+
+impl TrustedProxies {
+    /// Adds a comma separated list of CIDRs or single addresses
+    pub fn add(&mut self, list: &str) -> Result<(), Error> {
+        for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let net = match entry.parse::<IpNet>() {
+                Ok(n) => n,
+                Err(_) => IpNet::from(
+                    entry
+                        .parse::<IpAddr>()
+                        .with_context(|| format!("{} is not a CIDR or ip address", entry))?,
+                ),
+            };
+            self.nets.push(net);
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.nets.iter().any(|n| n.contains(ip))
+    }
+
+    /// The client is the first address from the right that isn't a trusted proxy,
+    /// everything left of it could have been made up by the client.
+    /// When every address is trusted, it's the leftmost one
+    pub fn client_ip(&self, chain: &[IpAddr]) -> Option<IpAddr> {
+        chain
+            .iter()
+            .rev()
+            .find(|ip| !self.contains(ip))
+            .or(chain.first())
+            .copied()
+    }
+}
+
+impl std::fmt::Display for TrustedProxies {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let nets: Vec<String> = self.nets.iter().map(|n| n.to_string()).collect();
+        write!(f, "{}", nets.join(", "))
+    }
+}
+
+/// Splits an X-Forwarded-For style list into the addresses, and the entries that aren't one.
+/// Ports are dropped, and nginx's `-` for a missing header is ignored
+pub fn parse_chain(field: &str) -> (Vec<IpAddr>, Vec<String>) {
+    let mut chain = vec![];
+    let mut invalid = vec![];
+    for entry in field.split(',').map(str::trim) {
+        if entry.is_empty() || entry == "-" {
+            continue;
+        }
+        if let Ok(ip) = entry.parse::<IpAddr>() {
+            chain.push(ip);
+        } else if let Ok(addr) = entry.parse::<SocketAddr>() {
+            chain.push(addr.ip());
+        } else {
+            invalid.push(entry.to_string());
+        }
+    }
+    (chain, invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ips(list: &[&str]) -> Vec<IpAddr> {
+        list.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn chains() {
+        let (chain, invalid) = parse_chain("84.213.100.23, unknown, 10.0.0.1:8080, [::1]:443, -");
+        assert_eq!(chain, ips(&["84.213.100.23", "10.0.0.1", "::1"]));
+        assert_eq!(invalid, vec!["unknown"]);
+        assert_eq!(parse_chain("-"), (vec![], vec![]));
+    }
+
+    #[test]
+    fn client_ips() {
+        let mut proxies = TrustedProxies::default();
+        proxies.add("10.0.0.0/8, 192.168.1.1").unwrap();
+        assert!(proxies.add("10.0.0.0/33").is_err());
+
+        // Whatever the client put in front of its own address is ignored
+        let chain = ips(&["1.1.1.1", "84.213.100.23", "10.0.0.2", "127.0.0.1"]);
+        assert_eq!(proxies.client_ip(&chain), Some(ips(&["84.213.100.23"])[0]));
+        let chain = ips(&["192.168.1.1", "10.0.0.2"]);
+        assert_eq!(proxies.client_ip(&chain), Some(chain[0]));
+        assert_eq!(proxies.client_ip(&[]), None);
+    }
+}
@@ -1,4 +1,6 @@
-use anyhow::{bail, Error, Result};
+use anyhow::{bail, Context, Error, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use chrono::{Duration, Utc};
 use colored::Colorize;
 use elasticsearch::auth::Credentials;
@@ -9,16 +11,23 @@ use elasticsearch::{BulkParts, CountParts, DeleteByQueryParts, Elasticsearch, Se
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
 use regex::Regex;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
 use reqwest::Client;
 use reqwest::{self, Url};
 use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::{fmt, io, thread, time};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{fmt, time};
 
-use crate::cert::Cert;
-use crate::logger::Logger;
+use crate::cert::{Cert, ClientIdentity};
+use crate::document::Document;
+use crate::errorlog::ErrorLog;
+use crate::index::{ilm_policy, policy_name, IndexOptions, Rollover};
+use crate::logger::{time_from_es, Logger};
 
 /// Checks if the string is an URL with regex
 pub fn is_url(str1: String) -> bool {
@@ -34,26 +43,114 @@ pub fn is_json(str: &str) -> Result<()> {
     Ok(())
 }
 
+/// How to authenticate against elasticsearch
+#[derive(Clone)]
+pub enum Auth {
+    None,
+    Basic(String, String),
+    /// Base64 encoded `id:key`, the way elasticsearch wants it in the header
+    ApiKey(String),
+    /// Bearer or service account token
+    Bearer(String),
+}
+
+impl Auth {
+    /// Accepts both `id:key` and the already encoded form
+    pub fn api_key(key: &str) -> Self {
+        if key.contains(':') {
+            Auth::ApiKey(STANDARD.encode(key))
+        } else {
+            Auth::ApiKey(key.to_string())
+        }
+    }
+
+    /// The Authorization header.
+    /// Sent as a plain header on both clients, since the credentials slot of the
+    /// elasticsearch transport is taken by the client certificate when using mutual TLS
+    fn header(&self) -> Option<HeaderValue> {
+        let value = match self {
+            Auth::None => return None,
+            Auth::Basic(u, p) => format!("Basic {}", STANDARD.encode(format!("{}:{}", u, p))),
+            Auth::ApiKey(k) => format!("ApiKey {}", k),
+            Auth::Bearer(t) => format!("Bearer {}", t),
+        };
+        let mut header = HeaderValue::from_str(&value).ok()?;
+        header.set_sensitive(true);
+        Some(header)
+    }
+}
+
+/// Never print the secrets themselves
+impl fmt::Display for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Auth::None => write!(f, "no auth"),
+            Auth::Basic(u, _) => write!(f, "basic auth as {}", u),
+            Auth::ApiKey(_) => write!(f, "api key"),
+            Auth::Bearer(_) => write!(f, "bearer token"),
+        }
+    }
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+/// Everything besides the url needed to connect to a server
+#[derive(Clone, Default)]
+pub struct ConnectionOptions {
+    pub cert_path: Option<PathBuf>,
+    /// Password for the username given in the url
+    pub password: Option<String>,
+    pub api_key: Option<String>,
+    pub bearer_token: Option<String>,
+    /// Client certificate for mutual TLS
+    pub client_identity: Option<ClientIdentity>,
+    /// Skip verifying the server certificate
+    pub insecure: bool,
+}
+
 /// Server, containing protocol, hostname, port and db
 #[derive(Clone)]
 pub struct Server {
     protocol: String,
-    username: Option<String>,
-    password: Option<String>,
+    pub auth: Auth,
     hostname: String,
     port: u16,
     index: String,
+    index_options: IndexOptions,
     client: Elasticsearch,
+    /// Plain http client for the requests outside the elasticsearch crate,
+    /// set up with the same certificates and auth
+    http: Client,
     pub cert: Option<Cert>,
+    /// Set once the error index is known to exist
+    error_index_ready: Arc<AtomicBool>,
+    /// Copies of this server for the other indices documents were routed to
+    routes: Arc<Mutex<HashMap<String, Server>>>,
 }
 
 impl Server {
-    pub fn new(url: &str, cert_path: Option<PathBuf>) -> Self {
+    pub fn new(url: &str, options: &ConnectionOptions, index_options: &IndexOptions) -> Self {
         let parsed_url = Url::parse(url).expect("Expected valid url");
 
         let protocol = parsed_url.scheme().to_string();
-        let username = Some(parsed_url.username().to_string());
-        let password = parsed_url.password().map(str::to_string);
+        let username = parsed_url.username().to_string();
+        let password = options
+            .password
+            .clone()
+            .or(parsed_url.password().map(str::to_string));
+        let auth = if let Some(t) = &options.bearer_token {
+            Auth::Bearer(t.clone())
+        } else if let Some(k) = &options.api_key {
+            Auth::api_key(k)
+        } else if let (false, Some(p)) = (username.is_empty(), password) {
+            Auth::Basic(username, p)
+        } else {
+            Auth::None
+        };
         let hostname = parsed_url.host_str().unwrap().to_string();
         let port = parsed_url.port().unwrap_or(9200);
         let index = parsed_url
@@ -67,50 +164,103 @@ impl Server {
         );
 
         let mut transport = TransportBuilder::new(pool);
+        let mut http = Client::builder().connect_timeout(time::Duration::from_secs(16));
         let mut cert = None;
-        if let Some(path) = cert_path {
+        if let Some(path) = options.cert_path.clone() {
             if let Ok(c) = Cert::new(path) {
+                for ca in &c.certs {
+                    http = http.add_root_certificate(ca.clone());
+                }
                 cert = Some(c.clone());
-                transport =
-                    transport.cert_validation(CertificateValidation::Certificate(c.es_cert));
+                // Full checks the hostname as well, like the http client does
+                transport = transport.cert_validation(CertificateValidation::Full(c.es_cert));
             }
         }
+        if options.insecure {
+            http = http.danger_accept_invalid_certs(true);
+            transport = transport.cert_validation(CertificateValidation::None);
+        }
 
-        if let (Some(u), Some(p)) = (username.clone(), password.clone()) {
-            transport = transport.auth(Credentials::Basic(u.to_string(), p.to_string()));
+        if let Some(identity) = &options.client_identity {
+            http = http.identity(
+                identity
+                    .identity()
+                    .expect("Expected valid client certificate"),
+            );
+            transport = transport.auth(Credentials::Certificate(identity.es_certificate()));
+        }
+
+        if let Some(header) = auth.header() {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, header.clone());
+            http = http.default_headers(headers);
+            transport = transport.header(AUTHORIZATION, header);
         }
 
         let client = Elasticsearch::new(transport.build().unwrap());
+        let http = http.build().expect("Expected a working http client");
 
         Server {
             protocol,
-            username,
-            password,
+            auth,
             hostname,
             port,
             index,
+            index_options: index_options.clone(),
             client,
+            http,
             cert,
+            error_index_ready: Arc::new(AtomicBool::new(false)),
+            routes: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    pub fn get_url_with_credentials(&self) -> String {
-        if let (Some(u), Some(p)) = (&self.username, &self.password) {
-            format!(
-                "{}://{}:{}@{}:{}/{}",
-                self.protocol, u, p, self.hostname, self.port, self.index
-            )
-        } else {
-            self.get_url()
+    /// The same server, writing to another index
+    pub fn with_index(&self, index: &str) -> Server {
+        Server {
+            index: index.to_string(),
+            error_index_ready: Arc::new(AtomicBool::new(false)),
+            routes: Arc::new(Mutex::new(HashMap::new())),
+            ..self.clone()
         }
     }
 
+    /// The server for documents routed to another index.
+    /// Those indices are checked at startup, like the one in the url
+    fn route(&self, index: &str) -> Server {
+        self.routes
+            .lock()
+            .unwrap()
+            .entry(index.to_string())
+            .or_insert_with(|| self.with_index(index))
+            .clone()
+    }
+
     pub fn get_url(&self) -> String {
         format!(
             "{}://{}:{}/{}",
             self.protocol, self.hostname, self.port, self.index
         )
     }
+    /// Where the error log lines go. Not `<index>-errors`, since that would match
+    /// the index template and the cleanup of the rolling indices
+    pub fn error_index(&self) -> String {
+        format!("{}_errors", self.index)
+    }
+
+    /// The index from the url, an alias when rolling indices
+    pub fn index(&self) -> &str {
+        &self.index
+    }
+
+    pub fn index_options(&self) -> &IndexOptions {
+        &self.index_options
+    }
+
+    pub fn http(&self) -> &Client {
+        &self.http
+    }
+
     pub fn get_host(&self) -> String {
         format!("{}://{}:{}", self.protocol, self.hostname, self.port)
     }
@@ -158,7 +308,24 @@ impl Server {
         return response_body.get("count").unwrap().as_i64().unwrap();
     }
 
-    async fn delete_before(&self, days_ago: u16) {
+    pub async fn delete_before(&self, days_ago: u16) {
+        if self.index_options.ilm {
+            println!(
+                "Leaving the deletion to the ILM policy {}",
+                policy_name(&self.index)
+            );
+            return;
+        }
+        if let Some(rollover) = self.index_options.rollover {
+            if let Err(e) = self.drop_indices_before(rollover, days_ago).await {
+                println!("{}", format!("Failed to drop old indices: {}", e).red());
+            }
+            // Only the index overlapping the cutoff can have anything left
+            if 0 >= self.count_before(days_ago).await {
+                return;
+            }
+        }
+
         let delete_query = self
             .client
             .delete_by_query(DeleteByQueryParts::Index(&[self.index.as_str()]))
@@ -182,7 +349,7 @@ impl Server {
 
         if !delete_query.is_ok() {
             println!("{}", "Failed to delete by query!".red());
-            thread::sleep(time::Duration::from_secs(6));
+            tokio::time::sleep(time::Duration::from_secs(6)).await;
             return;
         }
 
@@ -193,7 +360,7 @@ impl Server {
                 "{}",
                 "Delete by query responded with a non-zero response!".red()
             );
-            thread::sleep(time::Duration::from_secs(6));
+            tokio::time::sleep(time::Duration::from_secs(6)).await;
             return;
         }
 
@@ -201,6 +368,74 @@ impl Server {
         println!("{:?}", response_body);
     }
 
+    /// Drops whole indices that only contain documents older than the cutoff
+    async fn drop_indices_before(&self, rollover: Rollover, days_ago: u16) -> Result<(), Error> {
+        let cutoff = Utc::now().naive_utc().date() - Duration::days(days_ago.into());
+        let url = format!(
+            "{}/_cat/indices/{}-*?h=index&format=json",
+            self.get_host(),
+            self.index
+        );
+        let indices: Value = self.http.get(url).send().await?.json().await?;
+        let indices = indices
+            .as_array()
+            .with_context(|| format!("Unexpected response: {}", indices))?;
+
+        for name in indices.iter().filter_map(|i| i["index"].as_str()) {
+            match rollover.last_day(&self.index, name) {
+                Some(last_day) if cutoff > last_day => {}
+                _ => continue,
+            }
+            let response = self
+                .http
+                .delete(format!("{}/{}", self.get_host(), name))
+                .send()
+                .await?;
+            if response.status().is_success() {
+                println!("Dropped index {}", name);
+            } else {
+                println!(
+                    "{}",
+                    format!("Failed to drop index {}: {}", name, response.text().await?).red()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Error log lines aren't archived, they're just removed once they're old enough
+    pub async fn delete_errors_before(&self, days_ago: u16) -> Result<(), Error> {
+        let url = format!(
+            "{}/{}/_delete_by_query?ignore_unavailable=true",
+            self.get_host(),
+            self.error_index()
+        );
+        let response: Value = self
+            .http
+            .post(url)
+            .json(&json!({
+                "query": {
+                    "range": {
+                        "time": {
+                            "lt": format!("now-{}d/d", days_ago)
+                        }
+                    }
+                }
+            }))
+            .send()
+            .await?
+            .json()
+            .await?;
+        match response["deleted"].as_u64() {
+            Some(0) => {}
+            Some(deleted) => println!("Deleted {} old error log lines", deleted),
+            // The index doesn't exist yet
+            None if response["error"].is_null() => {}
+            None => bail!("Failed to delete old error log lines: {}", response),
+        }
+        Ok(())
+    }
+
     /// This function archives all documents before epoch time to an archive directory
     pub async fn archive(
         &self,
@@ -250,7 +485,8 @@ impl Server {
                                     "range": {
                                         "time": {
                                             "lt": format!("now-{}d/d", days_ago),
-                                            "gte": now * 1000
+                                            "gte": now,
+                                            "format": "epoch_millis"
                                         }
                                     }
                                 }
@@ -268,7 +504,7 @@ impl Server {
 
             if !search_response.is_ok() {
                 println!("{}", "Failed to search archive".red());
-                thread::sleep(time::Duration::from_secs(6));
+                tokio::time::sleep(time::Duration::from_secs(6)).await;
                 continue;
             }
 
@@ -279,7 +515,7 @@ impl Server {
                     "{}",
                     "Archive search responded with a non-zero response!".red()
                 );
-                thread::sleep(time::Duration::from_secs(6));
+                tokio::time::sleep(time::Duration::from_secs(6)).await;
                 continue;
             }
 
@@ -289,7 +525,7 @@ impl Server {
             if !failed.is_none() {
                 println!("{}", "Archiving search had errors!".red());
                 println!("{:?}", response_body);
-                thread::sleep(time::Duration::from_secs(6));
+                tokio::time::sleep(time::Duration::from_secs(6)).await;
                 continue;
             }
 
@@ -314,7 +550,7 @@ impl Server {
                     println!("Dcument doesn't have time ?");
                     continue;
                 }
-                now = item["_source"]["time"].as_u64().unwrap_or(0);
+                now = time_from_es(&item["_source"]["time"]).unwrap_or(0);
                 let id = String::from(item["_id"].as_str().unwrap_or("0"));
                 last.push(id.clone());
                 if last500.contains(&id) {
@@ -351,75 +587,137 @@ impl Server {
         Ok(())
     }
 
-    pub async fn bulk(&self, log: Vec<Logger>) {
+    /// Bulk indexes the documents, returning how many were created.
+    /// Documents routed to another index are sent there in a bulk of their own
+    pub async fn bulk(&self, log: Vec<Document>) -> Result<u32, Error> {
+        let mut groups: Vec<(Option<String>, Vec<Document>)> = vec![];
+        for document in log {
+            let index = document
+                .index()
+                .filter(|i| *i != self.index)
+                .map(String::from);
+            match groups.iter_mut().find(|(i, _)| *i == index) {
+                Some((_, documents)) => documents.push(document),
+                None => groups.push((index, vec![document])),
+            }
+        }
+
+        let mut counter = 0;
+        for (index, documents) in groups {
+            counter += match index {
+                Some(index) => self.route(&index).bulk_index(documents).await?,
+                None => self.bulk_index(documents).await?,
+            };
+        }
+        Ok(counter)
+    }
+
+    /// Bulk indexes the documents into this server's own index
+    async fn bulk_index(&self, log: Vec<Document>) -> Result<u32, Error> {
         let mut body: Vec<JsonBody<Value>> = vec![];
 
+        // The error index is only created once there's something to put in it
+        if !self.error_index_ready.load(Ordering::Relaxed)
+            && log.iter().any(|d| matches!(d, Document::Error(_)))
+        {
+            ErrorLog::ensure_index(self).await?;
+            self.error_index_ready.store(true, Ordering::Relaxed);
+        }
+
+        // Data streams are append only, and only accept create
+        let op = if self.index_options.data_stream {
+            "create"
+        } else {
+            "index"
+        };
+
         let mut ids: Vec<String> = vec![];
-        for elm in log {
-            let id = elm.get_id();
-            if !ids.contains(&id) {
-                body.push(json!({"index": {"_id": id}}).into());
-                body.push(json!(elm).into());
-                ids.push(id);
+        for document in log {
+            let id = document.id().map(String::from);
+            // The same id twice in a batch would only overwrite itself
+            if id.as_ref().is_some_and(|id| ids.contains(id)) {
+                continue;
+            }
+            let mut action = match &id {
+                Some(id) => json!({ "_id": id }),
+                None => json!({}),
+            };
+            ids.extend(id);
+            let elm = match document {
+                Document::Access(elm) => elm,
+                Document::Error(error) => {
+                    action["_index"] = json!(self.error_index());
+                    body.push(json!({ op: action }).into());
+                    body.push(json!(error).into());
+                    continue;
+                }
+            };
+            if let Some(r) = self.index_options.rollover {
+                action["_index"] = json!(r.index_name(&self.index, elm.time()));
+            }
+            body.push(json!({ op: action }).into());
+
+            let mut doc = json!(elm);
+            if self.index_options.data_stream {
+                doc["@timestamp"] = json!(elm.time());
             }
+            body.push(doc.into());
         }
 
         if body.is_empty() {
             println!("{}", "body is empty?".red());
-            return;
+            return Ok(0);
         }
 
+        let parts = match self.index_options.rollover {
+            Some(_) => BulkParts::None,
+            None => BulkParts::Index(self.index.as_str()),
+        };
         let response = self
             .client
-            .bulk(BulkParts::Index(self.index.as_str()))
+            .bulk(parts)
             .body(body)
             .request_timeout(time::Duration::from_secs(25))
             .send()
-            .await;
-
-        if !response.is_ok() {
-            println!("{}", "Failed to create bulk".red());
-            return;
-        }
+            .await
+            .context("Failed to create bulk")?;
 
-        let response = response.unwrap().json::<Value>().await;
-
-        if !response.is_ok() {
-            println!("{}", "Responded with a non-ok message!".red());
-            return;
-        }
-
-        let response_body = response.unwrap();
+        let response_body = response
+            .json::<Value>()
+            .await
+            .context("Responded with a non-ok message!")?;
 
         let successful = response_body["errors"].as_bool().unwrap_or(false) == false;
         if !successful {
             println!("{}", "Bulk had errors!".red());
         }
 
-        let items = response_body["items"].as_array();
-        if items.is_none() {
-            println!("{}", "Indexed 0 documents??".red());
-            return;
-        }
+        let items = response_body["items"]
+            .as_array()
+            .with_context(|| format!("Indexed 0 documents?? {}", response_body))?;
         let mut counter = 0;
-        for item in items.unwrap() {
-            if item.get("index").is_none() {
+        for item in items {
+            if item.get(op).is_none() {
                 continue;
             }
-            if item["index"].get("result").is_none() {
-                println!("{:?}", item);
+            if item[op].get("result").is_none() {
+                // Already existing documents in a data stream are conflicts, not errors
+                if item[op]["status"] != 409 {
+                    println!("{:?}", item);
+                }
                 continue;
             }
-            if item["index"]["result"].as_str().unwrap() != "created" {
+            if item[op]["result"].as_str().unwrap() != "created" {
                 continue;
             }
             counter += 1;
         }
         if counter == 0 {
             println!("{}", "0 documents was indexed!".red());
-            return;
+            return Ok(0);
         }
         println!("Successfully indexed {} documents", counter);
+        Ok(counter)
     }
 
     /// Checks if Elasticsearch database exists
@@ -428,53 +726,128 @@ impl Server {
             bail!("No index specified");
         }
         self.is_es().await?;
-        let url = format!(
-            "{}://{}:{}/{}",
-            self.protocol, self.hostname, self.port, self.index
-        );
-
-        let mut client_builder = Client::builder().connect_timeout(time::Duration::from_secs(16));
-
-        if let Some(cp) = &self.cert {
-            client_builder = client_builder.add_root_certificate(cp.cert.clone())
-        }
-
-        let client = client_builder.build()?;
-
-        let response = if let (Some(u), Some(p)) = (&self.username, &self.password) {
-            client.get(url.as_str()).basic_auth(u, Some(p)).send().await
-        } else {
-            client.get(url.as_str()).send().await
+        // When rolling indices or using a data stream, the template is what has to exist
+        let (url, what) = match self.index_options.uses_template() {
+            true => (
+                format!("{}/_index_template/{}", self.get_host(), self.index),
+                "index template",
+            ),
+            false => (self.get_url(), "index"),
         };
+
+        let response = self.http.get(url.as_str()).send().await;
         let res = response.unwrap();
         if res.status() != reqwest::StatusCode::OK {
             println!(
-                "  Found elasticsearch database, but index ({}) does not exist.",
-                self.index
+                "  Found elasticsearch database, but {} ({}) does not exist.",
+                what, self.index
             );
             println!(
                 "  Do you want to create {} at {}://{}:{} ?",
                 self.index, self.protocol, self.hostname, self.port
             );
-            print!("({}/{}/{}) > ", "y".green(), "n".red(), "q".yellow());
-            let _ = io::stdout().flush();
-            let mut user_input = String::new();
-            let stdin = io::stdin();
-            stdin.read_line(&mut user_input).expect("Expect input");
-            user_input = String::from(user_input.trim());
-            if user_input != "y" && user_input != "q" {
-                // if n or something else
+            if !self.index_options.prompt.confirm()? {
                 bail!("Cancelled due to user input");
-            } else if user_input == "q" {
-                println!("Quitting...");
-                std::process::exit(0);
-            } else if user_input == "y" {
-                Logger::create_mapping(self.clone()).await?;
-                return Ok(());
             }
-            bail!("Nothing happened");
+            if self.index_options.ilm {
+                self.install_policy().await?;
+            }
+            match self.index_options.uses_template() {
+                true => {
+                    self.alias_is_free().await?;
+                    Logger::create_template(self).await?
+                }
+                false => Logger::create_mapping(self.clone()).await?,
+            }
+            return Ok(());
+        }
+        match self.index_options.uses_template() {
+            true => {
+                self.alias_is_free().await?;
+                Logger::valid_template_mapping(self, res).await?;
+            }
+            false => Logger::valid_mapping(self.index.clone(), res).await?,
+        }
+        if self.index_options.ilm {
+            self.install_policy().await?;
+            self.verify_policy().await?;
+        }
+        Ok(())
+    }
+
+    /// Installs the ILM policy, unless it already exists
+    pub async fn install_policy(&self) -> Result<(), Error> {
+        let url = format!(
+            "{}/_ilm/policy/{}",
+            self.get_host(),
+            policy_name(&self.index)
+        );
+        if self.http.get(&url).send().await?.status() == reqwest::StatusCode::OK {
+            return Ok(());
+        }
+        // Rolling indices are already split by day or month
+        let policy = ilm_policy(
+            self.index_options.retention_days,
+            self.index_options.rollover.is_none(),
+        );
+        let res: Value = self
+            .http
+            .put(&url)
+            .json(&policy)
+            .send()
+            .await?
+            .json()
+            .await?;
+        if res["acknowledged"].as_bool() != Some(true) {
+            bail!("Failed to install ILM policy: {}", res);
+        }
+        println!("  Installed ILM policy {}", policy_name(&self.index));
+        Ok(())
+    }
+
+    /// Checks that every existing index has the expected ILM policy attached
+    async fn verify_policy(&self) -> Result<(), Error> {
+        let expected = policy_name(&self.index);
+        let url = format!(
+            "{}/{}/_settings/index.lifecycle.name",
+            self.get_host(),
+            self.index
+        );
+        let res = self.http.get(url).send().await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            // No indices yet, the template takes care of the new ones
+            return Ok(());
+        }
+        let j: Value = res.json().await?;
+        let indices = j
+            .as_object()
+            .with_context(|| format!("Unexpected response: {}", j))?;
+
+        let wrong: Vec<&String> = indices
+            .iter()
+            .filter(|(_, v)| v["settings"]["index"]["lifecycle"]["name"] != expected.as_str())
+            .map(|(k, _)| k)
+            .collect();
+        if !wrong.is_empty() {
+            bail!("ILM policy {} is not attached to: {:?}", expected, wrong);
+        }
+        Ok(())
+    }
+
+    /// Neither the alias nor the data stream can be created
+    /// if there is already a regular index with that name
+    async fn alias_is_free(&self) -> Result<(), Error> {
+        let res = self.http.get(self.get_url()).send().await?;
+        if res.status() != reqwest::StatusCode::OK {
+            return Ok(());
+        }
+        let j: Value = res.json().await?;
+        if j.get(&self.index).is_some() {
+            bail!(
+                "{} is a regular index, it can't be used as an alias or data stream",
+                self.index
+            );
         }
-        Logger::valid_mapping(self.index.clone(), res).await?;
         Ok(())
     }
 
@@ -484,22 +857,7 @@ impl Server {
 
         let url = format!("{}://{}:{}", self.protocol, self.hostname, self.port);
 
-        let mut client_builder = Client::builder().connect_timeout(time::Duration::from_secs(16));
-        if let Some(cp) = &self.cert {
-            client_builder = client_builder.add_root_certificate(cp.cert.clone());
-        }
-
-        let client = client_builder.build()?;
-
-        let response = if let (Some(u), Some(p)) = (self.username.clone(), self.password.clone()) {
-            client
-                .get(url.as_str())
-                .basic_auth(u, Some(p))
-                .send()
-                .await?
-        } else {
-            client.get(url.as_str()).send().await?
-        };
+        let response = self.http.get(url.as_str()).send().await?;
         if response.status() != 200 {
             bail!("Returned non-200 response: {:?}", response.status())
         }
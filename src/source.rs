@@ -0,0 +1,121 @@
+use anyhow::{bail, Context, Error, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::tail::matches_glob;
+
+/// Static fields, shared by all the documents they're for
+pub type Labels = Arc<BTreeMap<String, String>>;
+
+/// The static fields and the index for the lines of one source, given as
+/// `--source /var/log/nginx/shop-access.log,site=shop,env=prod,index=shop`
+#[derive(Clone, Debug)]
+pub struct SourceOptions {
+    /// A path, a glob pattern, `-` or `syslog://address/tag`
+    pub path: String,
+    pub labels: BTreeMap<String, String>,
+    pub index: Option<String>,
+    /// Together with the fields for all sources, set once they're all known
+    all_labels: Labels,
+}
+
+impl SourceOptions {
+    pub fn parse(spec: &str) -> Result<Self, Error> {
+        let mut parts = spec.split(',');
+        let path = parts.next().unwrap_or_default().trim();
+        if path.is_empty() {
+            bail!("Expected a path first, got {}", spec);
+        }
+        let mut options = SourceOptions {
+            path: path.to_string(),
+            labels: BTreeMap::new(),
+            index: None,
+            all_labels: Labels::default(),
+        };
+        for part in parts {
+            let (key, value) = parse_label(part)?;
+            match key.as_str() {
+                "index" => options.index = Some(value),
+                _ => {
+                    options.labels.insert(key, value);
+                }
+            }
+        }
+        Ok(options)
+    }
+
+    /// Checks if a line from this source (a path, `-` for stdin or syslog://...) is meant
+    pub fn matches(&self, source: &str) -> bool {
+        self.path == source || matches_glob(&self.path, Path::new(source))
+    }
+
+    /// Syslog messages come in on their own, there's nothing to read
+    pub fn is_syslog(&self) -> bool {
+        self.path.starts_with("syslog://")
+    }
+
+    /// The fields for all sources, with the ones of this source taking priority
+    pub fn merge_labels(&mut self, all: &BTreeMap<String, String>) {
+        let mut labels = all.clone();
+        labels.extend(self.labels.clone());
+        self.all_labels = Arc::new(labels);
+    }
+
+    pub fn all_labels(&self) -> &Labels {
+        &self.all_labels
+    }
+}
+
+impl fmt::Display for SourceOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path)?;
+        if !self.labels.is_empty() {
+            write!(f, " ({})", format_labels(&self.labels))?;
+        }
+        if let Some(index) = &self.index {
+            write!(f, " → {}", index)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `key=value`
+pub fn parse_label(spec: &str) -> Result<(String, String), Error> {
+    let (key, value) = spec
+        .split_once('=')
+        .with_context(|| format!("Expected key=value, got {}", spec))?;
+    let (key, value) = (key.trim(), value.trim());
+    if key.is_empty() || value.is_empty() {
+        bail!("Expected key=value, got {}", spec);
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// site=shop, env=prod
+pub fn format_labels(labels: &BTreeMap<String, String>) -> String {
+    let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    labels.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syslog_sources() {
+        let mut options = SourceOptions::parse("syslog://10.0.0.21/*,hostname=web1").unwrap();
+        assert!(options.is_syslog());
+        assert!(options.matches("syslog://10.0.0.21/nginx"));
+        assert!(!options.matches("syslog://10.0.0.22/nginx"));
+
+        let all = BTreeMap::from([
+            ("env".to_string(), "prod".to_string()),
+            ("hostname".to_string(), "all".to_string()),
+        ]);
+        options.merge_labels(&all);
+        assert_eq!(options.all_labels()["hostname"], "web1");
+        assert_eq!(options.all_labels()["env"], "prod");
+    }
+}
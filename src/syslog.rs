@@ -0,0 +1,338 @@
+use anyhow::{bail, Context, Error, Result};
+use regex::Regex;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc::Sender;
+
+use crate::document::Format;
+use crate::pipeline::Line;
+
+/// Large enough for any datagram
+const MAX_DATAGRAM: usize = 65536;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+/// An address to receive syslog messages on, like udp://0.0.0.0:514
+#[derive(Clone, Debug)]
+pub struct SyslogListener {
+    pub protocol: Protocol,
+    pub addr: SocketAddr,
+}
+
+impl SyslogListener {
+    /// Without a scheme it's udp, the only thing nginx itself can send
+    pub fn parse(str: &str) -> Result<Self, Error> {
+        let (protocol, addr) = match str.split_once("://") {
+            Some(("udp", addr)) => (Protocol::Udp, addr),
+            Some(("tcp", addr)) => (Protocol::Tcp, addr),
+            Some((scheme, _)) => bail!("Expected udp:// or tcp://, got {}://", scheme),
+            None => (Protocol::Udp, str),
+        };
+        let addr = addr
+            .parse()
+            .with_context(|| format!("{} is not an address with a port", addr))?;
+        Ok(SyslogListener { protocol, addr })
+    }
+
+    /// Receives messages until the socket fails, sending on what `handle` makes of them.
+    /// Nothing is read while the pipeline is full
+    pub async fn listen<F>(&self, lines: Sender<Line>, handle: F) -> Result<(), Error>
+    where
+        F: Fn(SocketAddr, &str) -> Option<Line> + Send + Sync + 'static,
+    {
+        match self.protocol {
+            Protocol::Udp => {
+                let socket = UdpSocket::bind(self.addr)
+                    .await
+                    .with_context(|| format!("Unable to listen on {}", self))?;
+                let mut buf = vec![0; MAX_DATAGRAM];
+                loop {
+                    let (len, peer) = socket.recv_from(&mut buf).await?;
+                    if let Some(line) = handle(peer, &String::from_utf8_lossy(&buf[..len])) {
+                        if lines.send(line).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+            Protocol::Tcp => {
+                let listener = TcpListener::bind(self.addr)
+                    .await
+                    .with_context(|| format!("Unable to listen on {}", self))?;
+                let handle = Arc::new(handle);
+                loop {
+                    let (stream, peer) = listener.accept().await?;
+                    let handle = handle.clone();
+                    let lines = lines.clone();
+                    tokio::task::spawn(async move {
+                        if let Err(e) = read_stream(stream, &lines, |line| handle(peer, line)).await
+                        {
+                            eprintln!("Syslog connection from {} failed: {}", peer, e);
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// What's done with the messages of one tag, given as `--syslog-tag shop=shop-logs,format=json`
+#[derive(Clone, Debug, Default)]
+pub struct SyslogTag {
+    pub index: Option<String>,
+    pub format: Format,
+}
+
+impl SyslogTag {
+    /// `tag=index`, `tag,format=json` or both. Returns the tag and what goes with it
+    pub fn parse(spec: &str) -> Result<(String, Self), Error> {
+        let mut parts = spec.split(',');
+        let first = parts.next().unwrap_or_default().trim();
+        let (tag, index) = match first.split_once('=') {
+            Some((tag, index)) => (tag.trim(), Some(index.trim())),
+            None => (first, None),
+        };
+        if tag.is_empty() || index.is_some_and(str::is_empty) {
+            bail!("Expected tag=index, got {}", spec);
+        }
+        let mut options = SyslogTag {
+            index: index.map(String::from),
+            format: Format::Auto,
+        };
+        for part in parts {
+            match part.trim().split_once('=') {
+                Some(("format", value)) => {
+                    options.format = Format::parse(value.trim()).with_context(|| {
+                        format!("Expected auto, access, json or error, got {}", value)
+                    })?
+                }
+                _ => bail!("Expected format=..., got {}", part),
+            }
+        }
+        if options.index.is_none() && options.format == Format::Auto {
+            bail!("Expected an index, a format or both for {}", tag);
+        }
+        Ok((tag.to_string(), options))
+    }
+}
+
+impl fmt::Display for SyslogTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.index {
+            Some(index) => write!(f, "→ {}", index)?,
+            None => write!(f, "→ the index in the url")?,
+        }
+        if self.format != Format::Auto {
+            write!(f, " ({} lines)", self.format)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SyslogListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.protocol {
+            Protocol::Udp => write!(f, "udp://{}", self.addr),
+            Protocol::Tcp => write!(f, "tcp://{}", self.addr),
+        }
+    }
+}
+
+/// Reads the messages of a tcp connection. They're either newline separated,
+/// or prefixed with their length as in RFC 6587 octet counting: `52 <190>1 ...`
+async fn read_stream(
+    stream: TcpStream,
+    lines: &Sender<Line>,
+    handle: impl Fn(&str) -> Option<Line>,
+) -> Result<(), Error> {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let starts_with_length = match reader.fill_buf().await?.first() {
+            None => return Ok(()),
+            Some(b) => b.is_ascii_digit(),
+        };
+        let mut message = vec![];
+        if starts_with_length {
+            let mut length = vec![];
+            reader.read_until(b' ', &mut length).await?;
+            let length: usize = String::from_utf8_lossy(&length)
+                .trim()
+                .parse()
+                .context("Invalid message length")?;
+            message.resize(length, 0);
+            reader.read_exact(&mut message).await?;
+        } else {
+            reader.read_until(b'\n', &mut message).await?;
+        }
+        let message = String::from_utf8_lossy(&message);
+        let message = message.trim_end_matches(['\r', '\n', '\0']);
+        if message.is_empty() {
+            continue;
+        }
+        if let Some(line) = handle(message) {
+            // The pipeline only goes away when everything stops
+            if lines.send(line).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// What's left of a syslog message once the header is taken off
+#[derive(Debug, PartialEq)]
+pub struct SyslogMessage {
+    pub hostname: Option<String>,
+    /// The program name, `nginx` unless set with `tag=` in nginx's syslog parameters
+    pub tag: Option<String>,
+    pub message: String,
+}
+
+impl SyslogMessage {
+    /// Parses both RFC 5424 and RFC 3164 (what nginx sends)
+    /// <190>1 2024-01-15T10:23:45.000Z web1 nginx - - - 1.2.3.4 - - [15/Jan/2024:10:23:45 +0000] ...
+    /// <190>Jan 15 10:23:45 web1 nginx: 1.2.3.4 - - [15/Jan/2024:10:23:45 +0000] ...
+    pub fn parse(raw: &str) -> Option<Self> {
+        let rfc5424 = Regex::new(
+            r#"^<\d{1,3}>1 \S+ (\S+) (\S+) \S+ \S+ (?:-|(?:\[(?:[^\]"]|"(?:[^"\\]|\\.)*")*\])+)(?: (.*))?$"#,
+        )
+        .unwrap();
+        if let Some(cap) = rfc5424.captures(raw) {
+            let message = cap.get(3).map_or("", |m| m.as_str());
+            return Some(SyslogMessage {
+                hostname: nil(&cap[1]),
+                tag: nil(&cap[2]),
+                message: message.trim_start_matches('\u{feff}').to_string(),
+            });
+        }
+
+        // The timestamp and hostname are left out by some relays
+        let rfc3164 = Regex::new(
+            r"^<\d{1,3}>(?:\w{3} [ \d]\d \d{2}:\d{2}:\d{2} )?(?:([^\s:]+) )?([^\s:\[]+)(?:\[\d+\])?: ?(.*)$",
+        )
+        .unwrap();
+        let cap = rfc3164.captures(raw)?;
+        Some(SyslogMessage {
+            hostname: cap.get(1).map(|h| h.as_str().to_string()),
+            tag: Some(cap[2].to_string()),
+            message: cap[3].to_string(),
+        })
+    }
+}
+
+/// `-` is how RFC 5424 leaves a field empty
+fn nil(value: &str) -> Option<String> {
+    match value {
+        "-" => None,
+        v => Some(v.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::mpsc;
+
+    const LINE: &str = r#"1.2.3.4 - - [15/Jan/2024:10:23:45 +0000] "example.com" "GET / HTTP/1.1" 200 5 "-" "curl""#;
+
+    #[test]
+    fn rfc3164() {
+        let message = SyslogMessage::parse(&format!("<190>Jan 15 10:23:45 web1 nginx: {}", LINE));
+        assert_eq!(
+            message,
+            Some(SyslogMessage {
+                hostname: Some("web1".to_string()),
+                tag: Some("nginx".to_string()),
+                message: LINE.to_string(),
+            })
+        );
+        // Relays can leave out the timestamp and hostname
+        let message = SyslogMessage::parse("<190>shop[123]: hello").unwrap();
+        assert_eq!(message.hostname, None);
+        assert_eq!(message.tag.as_deref(), Some("shop"));
+        assert_eq!(message.message, "hello");
+    }
+
+    #[test]
+    fn rfc5424() {
+        let raw = format!(
+            r#"<190>1 2024-01-15T10:23:45.000Z web1 nginx - - [meta a="x ] y"][b c="d"] {}"#,
+            LINE
+        );
+        let message = SyslogMessage::parse(&raw).unwrap();
+        assert_eq!(message.hostname.as_deref(), Some("web1"));
+        assert_eq!(message.tag.as_deref(), Some("nginx"));
+        assert_eq!(message.message, LINE);
+
+        let message = SyslogMessage::parse("<190>1 - - - - - - hello").unwrap();
+        assert_eq!(message.hostname, None);
+        assert_eq!(message.tag, None);
+        assert_eq!(message.message, "hello");
+    }
+
+    #[test]
+    fn not_syslog() {
+        assert_eq!(SyslogMessage::parse(LINE), None);
+    }
+
+    #[test]
+    fn tags() {
+        let (tag, options) = SyslogTag::parse("shop=shop-logs").unwrap();
+        assert_eq!(tag, "shop");
+        assert_eq!(options.index.as_deref(), Some("shop-logs"));
+        assert_eq!(options.format, Format::Auto);
+
+        let (_, options) = SyslogTag::parse("shop,format=json").unwrap();
+        assert_eq!(options.index, None);
+        assert_eq!(options.format, Format::Json);
+
+        assert!(SyslogTag::parse("shop").is_err());
+        assert!(SyslogTag::parse("shop=").is_err());
+        assert!(SyslogTag::parse("shop=x,format=yaml").is_err());
+    }
+
+    #[tokio::test]
+    async fn newlines_and_octet_counting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        // A counted message can contain newlines
+        client
+            .write_all(b"<190>web1 nginx: first\n11 <190>a: b\nc<190>a: last")
+            .await
+            .unwrap();
+        drop(client);
+
+        let (lines, mut received) = mpsc::channel(10);
+        read_stream(stream, &lines, |message| {
+            Some(Line {
+                text: message.to_string(),
+                source: String::new(),
+                offset: None,
+                index: None,
+                format: Format::Auto,
+            })
+        })
+        .await
+        .unwrap();
+        drop(lines);
+
+        let mut messages = vec![];
+        while let Some(line) = received.recv().await {
+            messages.push(line.text);
+        }
+        assert_eq!(
+            messages,
+            vec!["<190>web1 nginx: first", "<190>a: b\nc", "<190>a: last"]
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use std::io::{self, ErrorKind, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, BufReader};
+use tokio::sync::mpsc::Sender;
+use tokio::time::sleep;
+
+use crate::document::Format;
+use crate::pipeline::Line;
+
+/// How long to wait for more lines when at the end of the file
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often glob patterns are checked for new and deleted files
+pub const GLOB_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Follows a file like `tail -F`, across rotations and truncations
+pub struct Tailer {
+    path: PathBuf,
+    inode: u64,
+    /// Offset of the next line
+    pos: u64,
+    reader: BufReader<File>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Tailer {
+    /// Starts at the end of the file, or at the start for files that are new
+    pub async fn open(path: &Path, from_start: bool) -> Result<Self, io::Error> {
+        let file = File::open(path).await?;
+        let metadata = file.metadata().await?;
+        let pos = if from_start { 0 } else { metadata.len() };
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(pos)).await?;
+        Ok(Tailer {
+            path: path.to_path_buf(),
+            inode: metadata.ino(),
+            pos,
+            reader,
+            stop: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Setting it makes `watch` return, once it has read everything there is
+    pub fn stopper(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    /// Sends every new line with its offset in the file, until stopped.
+    /// While the pipeline is full the file isn't read any further
+    pub async fn watch(&mut self, lines: &Sender<Line>) {
+        let source = self.path.to_string_lossy().to_string();
+        let mut line = vec![];
+        loop {
+            match self.reader.read_until(b'\n', &mut line).await {
+                // Half a line means it's still being written, it's read again once complete
+                Ok(len) if len > 0 && line.ends_with(b"\n") => {
+                    let offset = self.pos;
+                    self.pos += len as u64;
+                    let text = String::from_utf8_lossy(&line);
+                    let next = Line {
+                        text: text.trim_end_matches(['\r', '\n']).to_string(),
+                        source: source.clone(),
+                        offset: Some(offset),
+                        index: None,
+                        format: Format::Auto,
+                    };
+                    if lines.send(next).await.is_err() {
+                        return;
+                    }
+                    line.clear();
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => println!("{}: {}", self.path.display(), e),
+            }
+            line.clear();
+            if self.stop.load(Ordering::Relaxed) {
+                return;
+            }
+            sleep(POLL_INTERVAL).await;
+            if let Err(e) = self.follow().await {
+                println!("{}: {}", self.path.display(), e);
+            }
+        }
+    }
+
+    /// Goes back to the start of the last complete line, switching to the new file
+    /// when the path now points to another one, or starting over when it was truncated
+    async fn follow(&mut self) -> Result<(), io::Error> {
+        match fs::metadata(&self.path).await {
+            Ok(m) if m.ino() != self.inode => {
+                // Whatever was written to the old file before it got rotated is read first
+                let mut rest = vec![];
+                self.reader.seek(SeekFrom::Start(self.pos)).await?;
+                if self.reader.read_to_end(&mut rest).await? > 0 && rest.ends_with(b"\n") {
+                    self.reader.seek(SeekFrom::Start(self.pos)).await?;
+                    return Ok(());
+                }
+                let file = File::open(&self.path).await?;
+                println!("reloading log file {}", self.path.display());
+                self.inode = file.metadata().await?.ino();
+                self.reader = BufReader::new(file);
+                self.pos = 0;
+            }
+            Ok(m) if m.len() < self.pos => {
+                println!("{} was truncated, starting over", self.path.display());
+                self.pos = 0;
+            }
+            // Gone for now, rotation might bring it back
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+            Ok(_) => {}
+        }
+        self.reader.seek(SeekFrom::Start(self.pos)).await?;
+        Ok(())
+    }
+}
+
+/// The files a glob pattern matches right now
+pub fn glob_files(pattern: &str) -> Vec<PathBuf> {
+    match glob::glob(pattern) {
+        Ok(paths) => paths
+            .filter_map(Result::ok)
+            .filter(|p| p.is_file())
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+pub fn matches_glob(pattern: &str, path: &Path) -> bool {
+    glob::Pattern::new(pattern).is_ok_and(|p| p.matches_path(path))
+}
+
+/// Checks if the argument is meant as a glob pattern instead of a path
+pub fn is_glob(str: &str) -> bool {
+    str.contains(['*', '?', '[']) && glob::Pattern::new(str).is_ok()
+}
@@ -0,0 +1,157 @@
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::OnceLock;
+
+/// How long the request took, and which upstreams handled it.
+/// Comes from key=value pairs after the user agent, like
+/// request_time=0.120 upstream_response_time=0.050, 0.068 upstream_status=502, 200
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Timing {
+    /// $request_time, in seconds
+    pub request_time: Option<f32>,
+    /// $upstream_response_time, one per upstream tried, in seconds.
+    /// Upstreams that weren't reached are null, so the positions line up with the status and address
+    pub upstream_response_time: Vec<Option<f32>>,
+    /// $upstream_status
+    pub upstream_status: Vec<Option<String>>,
+    /// $upstream_addr
+    pub upstream_addr: Vec<Option<String>>,
+    /// $bytes_sent, including the headers unlike $body_bytes_sent
+    pub bytes_sent: Option<u64>,
+}
+
+impl Timing {
+    /// Reads the pairs it knows from what's left of the line, the rest is ignored
+    pub fn parse(rest: &str) -> Self {
+        let mut timing = Timing::default();
+        for (key, value) in pairs(rest) {
+            timing.set(key, value);
+        }
+        timing
+    }
+
+    /// Sets a field by its name, unknown names are ignored
+    pub fn set(&mut self, key: &str, value: &str) {
+        match key {
+            "request_time" => self.request_time = value.parse().ok(),
+            "upstream_response_time" => {
+                self.upstream_response_time = upstream_values(value)
+                    .map(|v| v.and_then(|v| v.parse().ok()))
+                    .collect()
+            }
+            "upstream_status" => {
+                self.upstream_status = upstream_values(value)
+                    .map(|v| v.map(String::from))
+                    .collect()
+            }
+            "upstream_addr" => {
+                self.upstream_addr = upstream_values(value)
+                    .map(|v| v.map(String::from))
+                    .collect()
+            }
+            "bytes_sent" => self.bytes_sent = value.parse().ok(),
+            _ => {}
+        }
+    }
+
+    pub fn from_es(es: &Value) -> Self {
+        let strings = |key: &str| -> Vec<Option<String>> {
+            es.get(key)
+                .and_then(|v| v.as_array())
+                .map(|a| a.iter().map(|v| Some(v.as_str()?.to_string())).collect())
+                .unwrap_or_default()
+        };
+        Timing {
+            request_time: es
+                .get("request_time")
+                .and_then(|v| v.as_f64())
+                .map(|v| v as f32),
+            upstream_response_time: es
+                .get("upstream_response_time")
+                .and_then(|v| v.as_array())
+                .map(|a| a.iter().map(|v| Some(v.as_f64()? as f32)).collect())
+                .unwrap_or_default(),
+            upstream_status: strings("upstream_status"),
+            upstream_addr: strings("upstream_addr"),
+            bytes_sent: es.get("bytes_sent").and_then(|v| v.as_u64()),
+        }
+    }
+}
+
+/// The start of a `key=value` pair
+fn key_regex() -> &'static Regex {
+    static KEY: OnceLock<Regex> = OnceLock::new();
+    KEY.get_or_init(|| Regex::new(r"(?:^|\s)([a-z_]+)=").unwrap())
+}
+
+/// Splits `a=1 b=2, 3 c="x y"` into (a, 1), (b, 2, 3), (c, x y).
+/// Values run until the next key, since upstream values contain spaces
+fn pairs(rest: &str) -> Vec<(&str, &str)> {
+    let keys: Vec<_> = key_regex().captures_iter(rest).collect();
+    keys.iter()
+        .enumerate()
+        .map(|(i, cap)| {
+            let key = cap.get(1).unwrap();
+            let end = keys
+                .get(i + 1)
+                .map(|next| next.get(0).unwrap().start())
+                .unwrap_or(rest.len());
+            let value = rest[cap.get(0).unwrap().end()..end].trim();
+            (key.as_str(), value.trim_matches('"'))
+        })
+        .collect()
+}
+
+/// nginx separates the upstreams with commas, and upstream groups of internal redirects with colons.
+/// `-` is an upstream that wasn't reached, it's None to keep the positions the same in every field
+fn upstream_values(value: &str) -> impl Iterator<Item = Option<&str>> {
+    value
+        .split(',')
+        .flat_map(|v| v.split(" : "))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| (v != "-").then_some(v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pairs_run_until_the_next_key() {
+        assert_eq!(
+            pairs(r#" a=1 b=2, 3 c="x y""#),
+            vec![("a", "1"), ("b", "2, 3"), ("c", "x y")]
+        );
+        assert!(pairs(" nothing here").is_empty());
+    }
+
+    #[test]
+    fn timing_fields() {
+        let timing = Timing::parse(
+            " request_time=0.120 upstream_response_time=0.050, 0.068 upstream_status=502, 200 upstream_addr=10.0.0.1:80, 10.0.0.2:80 bytes_sent=512 other=x",
+        );
+        assert_eq!(timing.request_time, Some(0.12));
+        assert_eq!(timing.upstream_response_time, vec![Some(0.05), Some(0.068)]);
+        assert_eq!(
+            timing.upstream_status,
+            vec![Some("502".to_string()), Some("200".to_string())]
+        );
+        assert_eq!(timing.upstream_addr.len(), 2);
+        assert_eq!(timing.bytes_sent, Some(512));
+    }
+
+    #[test]
+    fn unreached_upstreams_keep_their_position() {
+        let timing = Timing::parse(
+            " upstream_response_time=-, 0.068 : 0.010 upstream_status=502, 200 : 404 upstream_addr=a:80, b:80 : c:80",
+        );
+        assert_eq!(
+            timing.upstream_response_time,
+            vec![None, Some(0.068), Some(0.01)]
+        );
+        assert_eq!(timing.upstream_status.len(), 3);
+        assert_eq!(timing.upstream_addr.len(), 3);
+    }
+}
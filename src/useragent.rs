@@ -0,0 +1,250 @@
+use anyhow::{Context, Error, Result};
+use regex::{Captures, Regex, RegexBuilder};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The regexes shipped with nginx-es, a subset of uap-core
+const EMBEDDED_REGEXES: &str = include_str!("../assets/regexes.yaml");
+
+/// Parsed user agents are cached, clients tend to send the same one over and over
+const CACHE_SIZE: usize = 10_000;
+
+/// What's left of the user agent after parsing
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Agent {
+    pub browser: String,
+    pub browser_version: Option<String>,
+    pub os: String,
+    pub os_version: Option<String>,
+    pub device: String,
+    pub is_bot: bool,
+}
+
+/// A regexes.yaml in the format of uap-core
+#[derive(Deserialize)]
+struct RegexFile {
+    user_agent_parsers: Vec<ParserEntry>,
+    os_parsers: Vec<ParserEntry>,
+    device_parsers: Vec<ParserEntry>,
+}
+
+/// All three kinds of parsers share the same layout, only the names of the replacements differ
+#[derive(Deserialize)]
+struct ParserEntry {
+    regex: String,
+    regex_flag: Option<String>,
+    #[serde(alias = "os_replacement", alias = "device_replacement")]
+    family_replacement: Option<String>,
+    #[serde(alias = "os_v1_replacement")]
+    v1_replacement: Option<String>,
+    #[serde(alias = "os_v2_replacement")]
+    v2_replacement: Option<String>,
+    #[serde(alias = "os_v3_replacement")]
+    v3_replacement: Option<String>,
+}
+
+struct Matcher {
+    regex: Regex,
+    family: Option<String>,
+    versions: [Option<String>; 3],
+}
+
+impl Matcher {
+    fn new(entry: ParserEntry) -> Result<Self, Error> {
+        let regex = RegexBuilder::new(&entry.regex)
+            .case_insensitive(entry.regex_flag.as_deref() == Some("i"))
+            .build()?;
+        Ok(Matcher {
+            regex,
+            family: entry.family_replacement,
+            versions: [
+                entry.v1_replacement,
+                entry.v2_replacement,
+                entry.v3_replacement,
+            ],
+        })
+    }
+
+    /// Family and version of the first matcher that matches, the uap-core way:
+    /// a replacement wins over the capture at the same position
+    fn find(matchers: &[Matcher], ua: &str) -> Option<(String, Option<String>)> {
+        matchers.iter().find_map(|m| {
+            let cap = m.regex.captures(ua)?;
+            let family = replace(&m.family, &cap, 1)?;
+            let versions: Vec<String> = m
+                .versions
+                .iter()
+                .enumerate()
+                .map_while(|(i, v)| replace(v, &cap, i + 2))
+                .collect();
+            let version = match versions.is_empty() {
+                true => None,
+                false => Some(versions.join(".")),
+            };
+            Some((family, version))
+        })
+    }
+}
+
+/// The replacement with $1..$9 filled in, or the capture at `group` without one
+fn replace(replacement: &Option<String>, cap: &Captures, group: usize) -> Option<String> {
+    let value = match replacement {
+        Some(r) => {
+            let mut value = r.clone();
+            for i in 1..=9 {
+                let capture = cap.get(i).map(|m| m.as_str()).unwrap_or("");
+                value = value.replace(&format!("${}", i), capture);
+            }
+            value
+        }
+        None => cap.get(group)?.as_str().to_string(),
+    };
+    let value = value.trim();
+    match value.is_empty() {
+        true => None,
+        false => Some(value.to_string()),
+    }
+}
+
+/// Parses user agents into browser, OS and device
+pub struct UserAgentParser {
+    browsers: Vec<Matcher>,
+    systems: Vec<Matcher>,
+    devices: Vec<Matcher>,
+    /// Regexes the regex crate can't compile (lookarounds and the like)
+    pub skipped: usize,
+    cache: Mutex<HashMap<String, Agent>>,
+}
+
+impl UserAgentParser {
+    /// Uses the regexes shipped with nginx-es
+    pub fn embedded() -> Result<Self, Error> {
+        UserAgentParser::from_yaml(EMBEDDED_REGEXES)
+    }
+
+    /// Uses a regexes.yaml from uap-core
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let yaml =
+            fs::read_to_string(path).with_context(|| format!("Unable to read {:?}", path))?;
+        UserAgentParser::from_yaml(&yaml)
+    }
+
+    fn from_yaml(yaml: &str) -> Result<Self, Error> {
+        let file: RegexFile = serde_yaml::from_str(yaml)?;
+        let mut skipped = 0;
+        let mut compile = |entries: Vec<ParserEntry>| -> Vec<Matcher> {
+            entries
+                .into_iter()
+                .filter_map(|e| match Matcher::new(e) {
+                    Ok(m) => Some(m),
+                    Err(_) => {
+                        skipped += 1;
+                        None
+                    }
+                })
+                .collect()
+        };
+        let browsers = compile(file.user_agent_parsers);
+        let systems = compile(file.os_parsers);
+        let devices = compile(file.device_parsers);
+        Ok(UserAgentParser {
+            browsers,
+            systems,
+            devices,
+            skipped,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Number of regexes in use
+    pub fn regex_count(&self) -> usize {
+        self.browsers.len() + self.systems.len() + self.devices.len()
+    }
+
+    pub fn parse(&self, ua: &str) -> Agent {
+        if let Some(agent) = self.cache.lock().unwrap().get(ua) {
+            return agent.clone();
+        }
+
+        let (browser, browser_version) =
+            Matcher::find(&self.browsers, ua).unwrap_or(("Other".to_string(), None));
+        let (os, os_version) =
+            Matcher::find(&self.systems, ua).unwrap_or(("Other".to_string(), None));
+        let device = Matcher::find(&self.devices, ua)
+            .map(|(d, _)| d)
+            .unwrap_or("Other".to_string());
+        let agent = Agent {
+            browser,
+            browser_version,
+            os,
+            os_version,
+            is_bot: device == "Spider",
+            device,
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        // Not worth keeping track of what's used the least, just start over
+        if cache.len() >= CACHE_SIZE {
+            cache.clear();
+        }
+        cache.insert(ua.to_string(), agent.clone());
+        agent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded_regexes() {
+        let parser = UserAgentParser::embedded().unwrap();
+        let agent =
+            parser.parse("Mozilla/5.0 (X11; Linux x86_64; rv:102.0) Gecko/20100101 Firefox/102.0");
+        assert_eq!(agent.browser, "Firefox");
+        assert_eq!(agent.browser_version.as_deref(), Some("102.0"));
+        assert_eq!(agent.os, "Linux");
+        assert!(!agent.is_bot);
+
+        let agent = parser
+            .parse("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)");
+        assert_eq!(agent.device, "Spider");
+        assert!(agent.is_bot);
+
+        let agent = parser.parse("something else");
+        assert_eq!(agent.browser, "Other");
+        assert_eq!(agent.os, "Other");
+        assert_eq!(agent.device, "Other");
+    }
+
+    #[test]
+    fn replacements() {
+        let yaml = r#"
+user_agent_parsers:
+  - regex: '(Shop)App/(\d+)\.(\d+)'
+    family_replacement: '$1 App'
+  - regex: '(?<=x)y'
+os_parsers:
+  - regex: '(Android) (\d+)'
+    os_replacement: 'Android'
+    os_v2_replacement: '0'
+device_parsers:
+  - regex: 'crawler'
+    regex_flag: 'i'
+    device_replacement: 'Spider'
+"#;
+        let parser = UserAgentParser::from_yaml(yaml).unwrap();
+        assert_eq!(parser.skipped, 1);
+        assert_eq!(parser.regex_count(), 3);
+
+        let agent = parser.parse("ShopApp/3.14 (Android 13)");
+        assert_eq!(agent.browser, "Shop App");
+        assert_eq!(agent.browser_version.as_deref(), Some("3.14"));
+        assert_eq!(agent.os, "Android");
+        assert_eq!(agent.os_version.as_deref(), Some("13.0"));
+        assert!(parser.parse("Some Crawler").is_bot);
+    }
+}
@@ -1,11 +1,13 @@
 use std::{
+    env,
     fs::{self, File},
     path::Path,
 };
 
 use anyhow::{bail, Error};
 use chrono::{DateTime, Local, TimeZone, Utc};
-use std::io::Write;
+use colored::Colorize;
+use std::io::{self, Write};
 
 /// Remove extra slashes in path
 /// From /home///chiya//something → /home/chiya/something/
@@ -55,10 +57,29 @@ pub fn valid_archive(loc: &str) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn epoch_to_datetime(epoch: i64) -> String {
-    let naive = Local.timestamp(epoch, 0).naive_local();
+/// Reads a secret from the argument value.
+/// `env:NAME` reads the environment variable, `file:/path` reads the file,
+/// anything else is taken literally
+pub fn read_secret(value: &str) -> Result<String, Error> {
+    if let Some(name) = value.strip_prefix("env:") {
+        match env::var(name) {
+            Ok(v) => Ok(v.trim().to_string()),
+            Err(_) => bail!("Environment variable {} is not set", name),
+        }
+    } else if let Some(path) = value.strip_prefix("file:") {
+        match fs::read_to_string(path) {
+            Ok(v) => Ok(v.trim().to_string()),
+            Err(e) => bail!("Unable to read {}: {}", path, e),
+        }
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+pub fn epoch_millis_to_datetime(epoch: i64) -> String {
+    let naive = Local.timestamp_millis(epoch).naive_local();
     let datetime = DateTime::<Utc>::from_local(naive, Utc);
-    let newdate = datetime.format("%Y-%m-%d %H:%M:%S").to_string();
+    let newdate = datetime.format("%Y-%m-%d %H:%M:%S%.3f").to_string();
     return newdate;
 }
 
@@ -87,3 +108,46 @@ pub fn dir_write_permission(path: String) -> bool {
     );
     true
 }
+
+/// Where the answers to the y/n/q questions come from
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Prompt {
+    /// Read from the terminal
+    #[default]
+    Ask,
+    /// --yes, everything is answered with y
+    Yes,
+    /// Stdin carries log lines or isn't a terminal, reading an answer would eat a line
+    NoTerminal,
+}
+
+impl Prompt {
+    /// Reads the answer to the question printed before, true for y.
+    /// q quits, without a terminal it fails instead of guessing
+    pub fn confirm(self) -> Result<bool, Error> {
+        match self {
+            Prompt::Yes => {
+                println!("({}) > y (--yes)", "y".green());
+                Ok(true)
+            }
+            Prompt::NoTerminal => bail!(
+                "Can't ask, stdin is a log source or not a terminal. Run with --yes to answer y"
+            ),
+            Prompt::Ask => {
+                print!("({}/{}/{}) > ", "y".green(), "n".red(), "q".yellow());
+                let _ = io::stdout().flush();
+                let mut user_input = String::new();
+                io::stdin().read_line(&mut user_input)?;
+                match user_input.trim() {
+                    "y" => Ok(true),
+                    "q" => {
+                        println!("Quitting...");
+                        std::process::exit(0);
+                    }
+                    // n or something else
+                    _ => Ok(false),
+                }
+            }
+        }
+    }
+}